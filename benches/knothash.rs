@@ -0,0 +1,247 @@
+extern crate rustacor;
+
+use rustacor::assembler;
+use rustacor::instruction::{DecodeError, Instruction, Parameter, Register};
+use rustacor::vm::{VM, VMError};
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 10;
+const INPUT: &'static str = "AoC 2017\n";
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+/// The interpreter loop from before the decode cache: every step decodes the instruction at `pc` into an
+/// `Instruction` and evaluates that directly. It's copied from `VM` as it was then, leaving out the undo log,
+/// tracing and `dmp`, which the benchmark doesn't use and which only cost a branch each when they're off.
+struct Baseline<'a> {
+    pc: u16,
+    registers: [u16; 8],
+    memory: [u16; 32768],
+    stack: Vec<u16>,
+    steps: u64,
+    input_callback: Box<FnMut() -> u16 + 'a>,
+    output_callback: Box<FnMut(u16) + 'a>
+}
+
+impl<'a> Baseline<'a> {
+    fn new(binary: &[u8], input_callback: Box<FnMut() -> u16 + 'a>) -> Self {
+        let mut memory = [0u16; 32768];
+        for (i, w) in binary.chunks(2).enumerate() {
+            memory[i] = w[0] as u16 | (w[1] as u16) << 8;
+        }
+        return Baseline {
+            pc: 0,
+            registers: [0; 8],
+            memory: memory,
+            stack: Vec::new(),
+            steps: 0,
+            input_callback: input_callback,
+            output_callback: Box::new(|_| {})
+        };
+    }
+
+    fn load_instruction(&mut self) -> Result<Instruction, VMError> {
+        let pc = self.pc as usize;
+        if pc >= self.memory.len() {
+            return Err(VMError::PcOutOfBounds(self.pc));
+        }
+        let instr = Instruction::decode(&self.memory[pc..]).map_err(|e| match e {
+            DecodeError::UnknownOpcode(i) => VMError::UnknownInstruction(i),
+            DecodeError::Truncated => VMError::PcOutOfBounds(self.pc),
+            DecodeError::InvalidOperand { word, .. } => VMError::InvalidOperand { pc: self.pc, word: word }
+        })?;
+        self.pc += instr.len();
+        Ok(instr)
+    }
+
+    fn get_register(&self, reg: &Register) -> Result<u16, VMError> {
+        if reg.0 >= 8 {return Err(VMError::OOBRegister(reg.0 as u16))}
+        return Ok(self.registers[reg.0 as usize]);
+    }
+
+    fn set_register(&mut self, reg: &Register, v: u16) -> Result<(), VMError> {
+        if reg.0 >= 8 {return Err(VMError::OOBRegister(reg.0 as u16))}
+        self.registers[reg.0 as usize] = v;
+        Ok(())
+    }
+
+    fn get_parameter(&self, param: &Parameter) -> Result<u16, VMError> {
+        match *param {
+            Parameter::Register(ref r) => self.get_register(r),
+            Parameter::Literal(l) => Ok(l),
+            Parameter::Label(_) | Parameter::Constant(_) | Parameter::Expr(_) => unreachable!("labels are rejected before evaluation")
+        }
+    }
+
+    fn stack_pop(&mut self) -> Result<u16, VMError> {
+        self.stack.pop().ok_or(VMError::PopFromEmptyStack)
+    }
+
+    fn evaluate(&mut self, pc: u16, instr: Instruction) -> Result<bool, VMError> {
+        if instr.has_label() {
+            unreachable!("decoded instructions have no labels");
+        }
+
+        match instr {
+            Instruction::Halt => return Ok(false),
+            Instruction::Set(ref a, ref b) => {
+                let v = self.get_parameter(b)?;
+                self.set_register(a, v)?
+            }
+            Instruction::Push(ref a) => {
+                let v = self.get_parameter(a)?;
+                self.stack.push(v);
+            }
+            Instruction::Pop(ref a) => {
+                let v = self.stack_pop()?;
+                self.set_register(a, v)?;
+            }
+            Instruction::Eq(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)? == self.get_parameter(c)?;
+                self.set_register(a, if v { 1 } else { 0 })?;
+            }
+            Instruction::Gt(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)? > self.get_parameter(c)?;
+                self.set_register(a, if v { 1 } else { 0 })?;
+            }
+            Instruction::Jmp(ref a) => self.pc = self.get_parameter(a)?,
+            Instruction::Jt(ref a, ref b) => {
+                if self.get_parameter(a)? != 0 {
+                    self.pc = self.get_parameter(b)?;
+                }
+            }
+            Instruction::Jf(ref a, ref b) => {
+                if self.get_parameter(a)? == 0 {
+                    self.pc = self.get_parameter(b)?;
+                }
+            }
+            Instruction::Add(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)?.wrapping_add(self.get_parameter(c)?);
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::Mult(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)?.wrapping_mul(self.get_parameter(c)?);
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::Mod(ref a, ref b, ref c) => {
+                let divisor = self.get_parameter(c)?;
+                if divisor == 0 {
+                    return Err(VMError::DivisionByZero { pc: pc, instruction: instr.clone() });
+                }
+                let v = self.get_parameter(b)? % divisor;
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::And(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)? & self.get_parameter(c)?;
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::Or(ref a, ref b, ref c) => {
+                let v = self.get_parameter(b)? | self.get_parameter(c)?;
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::Not(ref a, ref b) => {
+                let v = !self.get_parameter(b)?;
+                self.set_register(a, v % 32768)?
+            }
+            Instruction::Rmem(ref a, ref b) => {
+                let address = self.get_parameter(b)?;
+                let v = match self.memory.get(address as usize) {
+                    Some(v) => *v,
+                    None => return Err(VMError::MemoryOutOfBounds { pc: pc, instruction: instr.clone(), address: address })
+                };
+                self.set_register(a, v)?;
+            }
+            Instruction::Wmem(ref a, ref b) => {
+                let address = self.get_parameter(a)?;
+                let v = self.get_parameter(b)?;
+                if address as usize >= self.memory.len() {
+                    return Err(VMError::MemoryOutOfBounds { pc: pc, instruction: instr.clone(), address: address });
+                }
+                self.memory[address as usize] = v;
+            }
+            Instruction::Call(ref a) => {
+                let pc = self.pc;
+                self.stack.push(pc);
+                self.pc = self.get_parameter(a)?;
+            }
+            Instruction::Ret => {
+                self.pc = self.stack_pop()?;
+            }
+            Instruction::Out(ref a) => {
+                let param = self.get_parameter(a)?;
+                (self.output_callback)(param);
+            }
+            Instruction::In(ref a) => {
+                let v = (self.input_callback)();
+                self.set_register(a, v)?;
+            },
+            Instruction::Dmp | Instruction::Noop => {}
+        }
+        return Ok(true);
+    }
+
+    fn execute(&mut self) -> Result<(), VMError> {
+        loop {
+            let pc = self.pc;
+            let instr = self.load_instruction()?;
+            self.steps += 1;
+            if !self.evaluate(pc, instr)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Runs the program to completion, returning how long it took and how many instructions it executed.
+fn run_baseline(binary: &[u8]) -> (Duration, u64) {
+    let mut input = INPUT.chars();
+    let mut vm = Baseline::new(binary, Box::new(move || input.next().map_or(0, |c| c as u16)));
+
+    let start = Instant::now();
+    vm.execute().expect("knothash failed");
+    (start.elapsed(), vm.steps)
+}
+
+fn run(binary: &[u8], cache: bool) -> (Duration, u64) {
+    let mut slc = binary;
+    let mut vm = VM::new_from_reader(&mut slc);
+    vm.set_decode_cache(cache);
+    let mut input = INPUT.chars();
+    vm.set_input_callback(move || input.next().map_or(0, |c| c as u16));
+
+    let start = Instant::now();
+    vm.execute().expect("knothash failed");
+    (start.elapsed(), vm.steps())
+}
+
+/// Compares the VM with and without its decoded instruction cache against the interpreter from before the cache.
+/// With the cache off, the VM still decodes into its internal form after building an `Instruction`, so it's
+/// slower than the baseline; the speedup is measured against the baseline.
+fn main() {
+    let src = include_str!("../examples/knothash.synasm");
+    let assembly = assembler::assemble_program("knothash.synasm", src).expect("Unable to assemble knothash.synasm");
+    let mut binary = Vec::new();
+    assembly.write(&mut binary);
+
+    let mut times = Vec::new();
+    for &(name, cache) in &[("baseline", None), ("cache off", Some(false)), ("cache on", Some(true))] {
+        let mut total = 0.0;
+        let mut steps = 0;
+        for _ in 0..RUNS {
+            let (time, n) = match cache {
+                Some(cache) => run(&binary, cache),
+                None => run_baseline(&binary)
+            };
+            total += millis(time);
+            steps = n;
+        }
+        let per_run = total / RUNS as f64;
+        println!("{:>10}: {:8.2} ms per run, {} instructions, {:.1}M instructions/s",
+                 name, per_run, steps, steps as f64 / per_run / 1000.0);
+        times.push(per_run);
+    }
+    println!("The cache makes knothash {:.2}x as fast as the baseline", times[0] / times[2]);
+}
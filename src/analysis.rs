@@ -0,0 +1,271 @@
+use ::disassembler::decode;
+use ::instruction::{Instruction, Parameter};
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+/// A run of instructions that is only entered at its start and only branches at its end.
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    /// Where execution continues after the last instruction if it doesn't branch away.
+    pub fallthrough: Option<u16>
+}
+
+impl Block {
+    /// The address just past the last instruction.
+    pub fn end(&self) -> u16 {
+        match self.instructions.last() {
+            Some(&(adr, ref instr)) => adr + instr.len(),
+            None => self.start
+        }
+    }
+
+    fn last(&self) -> &Instruction {
+        &self.instructions.last().unwrap().1
+    }
+
+    /// The literal target of the branch the block ends with, other than a call.
+    pub fn branch_target(&self) -> Option<u16> {
+        match *self.last() {
+            Instruction::Jmp(Parameter::Literal(t)) |
+            Instruction::Jt(_, Parameter::Literal(t)) |
+            Instruction::Jf(_, Parameter::Literal(t)) => Some(t),
+            _ => None
+        }
+    }
+
+    /// The function the block calls at its end, if it's a call to a literal address.
+    pub fn call_target(&self) -> Option<u16> {
+        match *self.last() {
+            Instruction::Call(Parameter::Literal(t)) => Some(t),
+            _ => None
+        }
+    }
+
+    /// Whether the block ends with a jump, call or return to an address computed at run time.
+    pub fn is_computed(&self) -> bool {
+        match *self.last() {
+            Instruction::Jmp(Parameter::Register(_)) |
+            Instruction::Jt(_, Parameter::Register(_)) |
+            Instruction::Jf(_, Parameter::Register(_)) |
+            Instruction::Call(Parameter::Register(_)) |
+            Instruction::Ret => true,
+            _ => false
+        }
+    }
+
+    /// The blocks execution can continue with inside the same function. Calls count as returning to the next block.
+    pub fn successors(&self) -> Vec<u16> {
+        self.branch_target().into_iter().chain(self.fallthrough).collect()
+    }
+}
+
+/// Whether `instr` ends a basic block, because execution may continue somewhere other than the next instruction.
+pub fn is_terminator(instr: &Instruction) -> bool {
+    match *instr {
+        Instruction::Halt | Instruction::Jmp(_) | Instruction::Jt(..) | Instruction::Jf(..) |
+        Instruction::Call(_) | Instruction::Ret => true,
+        _ => false
+    }
+}
+
+/// Finds the basic blocks reachable from address 0 by following every branch with a literal target.
+/// Code only reached through computed jumps isn't found. Words past the 32768 the VM can address are ignored.
+pub fn find_blocks(memory: &[u16]) -> Vec<Block> {
+    let memory = &memory[..cmp::min(memory.len(), 32768)];
+    let mut leaders: BTreeSet<u16> = BTreeSet::new();
+    let mut seen: BTreeSet<u16> = BTreeSet::new();
+    let mut todo = vec![0u16];
+    leaders.insert(0);
+
+    while let Some(adr) = todo.pop() {
+        if !seen.insert(adr) {
+            continue;
+        }
+        let instr = match decode(memory, adr) {
+            Some(instr) => instr,
+            None => continue
+        };
+        let next = adr + instr.len();
+        let (target, falls_through) = match instr {
+            Instruction::Halt | Instruction::Ret => (None, false),
+            Instruction::Jmp(ref a) => (Some(a.clone()), false),
+            Instruction::Jt(_, ref b) | Instruction::Jf(_, ref b) => (Some(b.clone()), true),
+            Instruction::Call(ref a) => (Some(a.clone()), true),
+            _ => (None, true)
+        };
+        if let Some(Parameter::Literal(t)) = target {
+            leaders.insert(t);
+            todo.push(t);
+        }
+        if falls_through && (next as usize) < memory.len() {
+            if is_terminator(&instr) {
+                leaders.insert(next);
+            }
+            todo.push(next);
+        }
+    }
+
+    let mut blocks = Vec::new();
+    for &start in &leaders {
+        let mut block = Block { start: start, instructions: Vec::new(), fallthrough: None };
+        let mut adr = start;
+        while let Some(instr) = decode(memory, adr) {
+            let next = adr + instr.len();
+            let done = is_terminator(&instr);
+            let falls_through = match instr {
+                Instruction::Halt | Instruction::Ret | Instruction::Jmp(_) => false,
+                _ => true
+            };
+            block.instructions.push((adr, instr));
+            if done || leaders.contains(&next) {
+                block.fallthrough = if falls_through { Some(next) } else { None };
+                break;
+            }
+            adr = next;
+        }
+        if block.instructions.is_empty() {
+            continue;
+        }
+        if block.fallthrough.is_none() && !is_terminator(&block.instructions.last().unwrap().1) {
+            // Runs into something that doesn't decode, which the interpreter reports if it's ever reached
+            block.fallthrough = Some(block.end());
+        }
+        blocks.push(block);
+    }
+    return blocks;
+}
+
+/// The blocks reachable from an entry point without following calls, which are treated as returning.
+pub struct Function {
+    pub entry: u16,
+    /// The start of every block in the function, in address order.
+    pub blocks: Vec<u16>
+}
+
+/// The control-flow graph of a binary: its basic blocks and the functions they make up.
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    pub functions: Vec<Function>
+}
+
+impl Cfg {
+    /// Analyzes a binary from address 0. Every literal call target is the entry point of a function,
+    /// and so is address 0.
+    pub fn new(memory: &[u16]) -> Cfg {
+        let blocks: BTreeMap<u16, Block> = find_blocks(memory).into_iter().map(|b| (b.start, b)).collect();
+
+        let mut entries: BTreeSet<u16> = blocks.values().filter_map(|b| b.call_target()).collect();
+        entries.insert(0);
+        let functions = entries.into_iter()
+            .filter(|e| blocks.contains_key(e))
+            .map(|entry| {
+                let mut seen = BTreeSet::new();
+                let mut todo = vec![entry];
+                while let Some(adr) = todo.pop() {
+                    if let Some(block) = blocks.get(&adr) {
+                        if seen.insert(adr) {
+                            todo.extend(block.successors());
+                        }
+                    }
+                }
+                Function { entry: entry, blocks: seen.into_iter().collect() }
+            })
+            .collect();
+
+        return Cfg {
+            blocks: blocks,
+            functions: functions
+        };
+    }
+
+    /// Writes the graph in Graphviz DOT format, with one cluster per function. Blocks shared between functions
+    /// are drawn in the one with the lowest entry point. Call edges are dashed, and blocks that end with a
+    /// computed jump or a return have a double border.
+    pub fn write_dot(&self, out: &mut Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        let mut drawn = BTreeSet::new();
+        for function in &self.functions {
+            writeln!(out, "    subgraph cluster_{:04x} {{", function.entry)?;
+            writeln!(out, "        label=\"L_{:04x}\";", function.entry)?;
+            for adr in &function.blocks {
+                if drawn.insert(*adr) {
+                    self.write_dot_block(out, &self.blocks[adr])?;
+                }
+            }
+            writeln!(out, "    }}")?;
+        }
+
+        for block in self.blocks.values() {
+            if !drawn.contains(&block.start) {
+                self.write_dot_block(out, block)?;
+            }
+            let (taken, not_taken) = match *block.last() {
+                Instruction::Jt(..) => (" [label=\"true\"]", " [label=\"false\"]"),
+                Instruction::Jf(..) => (" [label=\"false\"]", " [label=\"true\"]"),
+                _ => ("", "")
+            };
+            if let Some(t) = block.branch_target().filter(|t| self.blocks.contains_key(t)) {
+                writeln!(out, "    b_{:04x} -> b_{:04x}{};", block.start, t, taken)?;
+            }
+            if let Some(t) = block.call_target().filter(|t| self.blocks.contains_key(t)) {
+                writeln!(out, "    b_{:04x} -> b_{:04x} [style=dashed];", block.start, t)?;
+            }
+            if let Some(t) = block.fallthrough.filter(|t| self.blocks.contains_key(t)) {
+                writeln!(out, "    b_{:04x} -> b_{:04x}{};", block.start, t, not_taken)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_dot_block(&self, out: &mut Write, block: &Block) -> io::Result<()> {
+        let mut label = String::new();
+        for &(adr, ref instr) in &block.instructions {
+            let text = instr.to_string().replace('\\', "\\\\").replace('"', "\\\"");
+            label.push_str(&format!("x{:04x}: {}\\l", adr, text));
+        }
+        let style = if block.is_computed() { ", peripheries=2" } else { "" };
+        writeln!(out, "        b_{:04x} [label=\"{}\"{}];", block.start, label, style)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::tests::image;
+
+    const PROGRAM: &'static str = "    in $0\n    jt $0 :odd\n    call :f\nend: halt\nodd: jf $0 :end\n    halt\nf:  out $0\n    ret\n";
+
+    #[test]
+    fn splits_blocks_and_functions() {
+        let cfg = Cfg::new(&image(PROGRAM));
+        let blocks: Vec<(u16, u16, Vec<u16>)> = cfg.blocks.values().map(|b| (b.start, b.end(), b.successors())).collect();
+        assert_eq!(blocks, vec![(0, 5, vec![8, 5]), (5, 7, vec![7]), (7, 8, vec![]), (8, 11, vec![7, 11]),
+                                (11, 12, vec![]), (12, 15, vec![])]);
+        assert_eq!(cfg.blocks[&5].call_target(), Some(12));
+        assert!(cfg.blocks[&12].is_computed());
+        assert!(!cfg.blocks[&8].is_computed());
+
+        let functions: Vec<(u16, Vec<u16>)> = cfg.functions.iter().map(|f| (f.entry, f.blocks.clone())).collect();
+        assert_eq!(functions, vec![(0, vec![0, 5, 7, 8, 11]), (12, vec![12])]);
+    }
+
+    #[test]
+    fn writes_dot() {
+        let mut out = Vec::new();
+        Cfg::new(&image(PROGRAM)).write_dot(&mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.starts_with("digraph cfg {\n"));
+        assert!(dot.ends_with("}\n"));
+        for line in &["    subgraph cluster_0000 {", "    subgraph cluster_000c {",
+                      "    b_0000 -> b_0008 [label=\"true\"];", "    b_0000 -> b_0005 [label=\"false\"];",
+                      "    b_0005 -> b_000c [style=dashed];", "    b_0008 -> b_0007 [label=\"false\"];",
+                      "        b_000c [label=\"x000c: out $0\\lx000e: ret\\l\", peripheries=2];"] {
+            assert!(dot.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+}
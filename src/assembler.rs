@@ -1,103 +1,816 @@
-use ::instruction::{Instruction, Parameter};
+use ::instruction::{BinOp, Expr, Instruction, Parameter, Register};
+use ::parser::Statement;
+use ::source::{Diagnostic, SourceMap, Span};
 
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{BTreeMap, HashMap};
+use std::{cmp, fmt};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ProgramElement {
     Label(String),
     Instruction(Instruction),
-    Data(Vec<u16>)
+    Data(Vec<u16>),
+    /// A `.equ` definition. Takes up no space in the output.
+    Constant(String, Parameter),
+    /// `.org`, which places everything after it at the given address.
+    Org(Parameter),
+    /// `.align`, which skips ahead to the next multiple of the given number of words.
+    Align(Parameter)
 }
 
 impl ProgramElement {
     fn size(&self) -> u16 {
         match *self {
-            ProgramElement::Label(_) => 0,
+            ProgramElement::Label(_) | ProgramElement::Constant(..) |
+            ProgramElement::Org(_) | ProgramElement::Align(_) => 0,
             ProgramElement::Instruction(ref instr) => instr.len() as u16,
             ProgramElement::Data(ref  v) => v.len() as u16
         }
     }
+
+    /// The words this element assembles to, exactly as they are written to the output.
+    fn words(&self) -> Vec<u16> {
+        match *self {
+            ProgramElement::Label(_) | ProgramElement::Constant(..) |
+            ProgramElement::Org(_) | ProgramElement::Align(_) => Vec::new(),
+            ProgramElement::Instruction(ref instr) => {
+                let mut buf = Vec::new();
+                instr.write(&mut buf);
+                let mut slc: &[u8] = &buf;
+                let mut words = Vec::new();
+                while let Ok(v) = slc.read_u16::<LittleEndian>() {
+                    words.push(v);
+                }
+                words
+            },
+            ProgramElement::Data(ref v) => v.clone()
+        }
+    }
 }
 
-fn locate_labels(elems: &Vec<ProgramElement>) -> HashMap<String, u16> {
-    let mut acc = 0u16;
-    let mut map: HashMap<String, u16> = HashMap::new();
+/// A program element along with where it came from in the source.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SourceElement {
+    pub span: Span,
+    /// For instructions, the span of every operand in the same order as `Instruction::operands`.
+    pub operands: Vec<Span>,
+    pub elem: ProgramElement,
+    /// Whether this was produced by lowering a pseudo-instruction, rather than written in the source.
+    pub generated: bool
+}
+
+fn is_numeric_label(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Splits a numeric label reference like `1f` into the label and the direction.
+fn numeric_ref(name: &str) -> Option<(&str, &str)> {
+    if name.len() > 1 && (name.ends_with('f') || name.ends_with('b')) && is_numeric_label(&name[..(name.len() - 1)]) {
+        Some(name.split_at(name.len() - 1))
+    } else {
+        None
+    }
+}
+
+/// Rewrites a reference to a local or numeric label to the name `qualify_labels` gave its definition.
+/// `seen` is how many times each numeric label has been defined so far, and `totals` how often it is defined at all.
+/// References that can't be resolved are left alone, and reported as undefined later.
+fn qualify_ref(param: &mut Parameter, scope: &Option<String>, seen: &HashMap<String, usize>, totals: &HashMap<String, usize>) {
+    match *param {
+        Parameter::Label(ref mut name) => {
+            let qualified = if name.starts_with('.') {
+                scope.as_ref().map(|g| format!("{}{}", g, name))
+            } else if let Some((num, dir)) = numeric_ref(name) {
+                let count = seen.get(num).cloned().unwrap_or(0);
+                match dir {
+                    "f" if count < totals.get(num).cloned().unwrap_or(0) => Some(format!("{}#{}", num, count)),
+                    "b" if count > 0 => Some(format!("{}#{}", num, count - 1)),
+                    _ => None
+                }
+            } else {
+                None
+            };
+            if let Some(q) = qualified {
+                *name = q;
+            }
+        },
+        Parameter::Expr(ref mut e) => qualify_expr(e, scope, seen, totals),
+        _ => {}
+    }
+}
+
+fn qualify_expr(expr: &mut Expr, scope: &Option<String>, seen: &HashMap<String, usize>, totals: &HashMap<String, usize>) {
+    match *expr {
+        Expr::Term(ref mut p) => qualify_ref(p, scope, seen, totals),
+        Expr::Binary(_, ref mut a, ref mut b) => {
+            qualify_expr(a, scope, seen, totals);
+            qualify_expr(b, scope, seen, totals);
+        }
+    }
+}
+
+/// Gives local and numeric labels unique names. `.loop` after `init:` becomes `init.loop`, and the n-th
+/// definition of `1:` becomes `1#n`, with `:1f` and `:1b` referring to the next and previous one.
+fn qualify_labels(elems: &mut Vec<SourceElement>, errors: &mut Vec<(Span, String)>) {
+    let mut totals: HashMap<String, usize> = HashMap::new();
+    for elem in elems.iter() {
+        if let ProgramElement::Label(ref name) = elem.elem {
+            if is_numeric_label(name) {
+                *totals.entry(name.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut scope: Option<String> = None;
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for elem in elems.iter_mut() {
+        let span = elem.span;
+        let mut params = match elem.elem {
+            ProgramElement::Label(ref mut name) => {
+                if is_numeric_label(name) {
+                    let n = seen.entry(name.clone()).or_insert(0);
+                    *name = format!("{}#{}", name, n);
+                    *n += 1;
+                } else if name.starts_with('.') {
+                    match scope {
+                        Some(ref g) => *name = format!("{}{}", g, name),
+                        None => errors.push((span, format!("local label `{}` has no global label before it", name)))
+                    }
+                } else if !name.contains('@') {
+                    // Labels from macro expansions don't start a new scope
+                    scope = Some(name.clone());
+                }
+                continue;
+            },
+            ProgramElement::Instruction(ref mut instr) => instr.params_mut(),
+            ProgramElement::Constant(_, ref mut value) |
+            ProgramElement::Org(ref mut value) | ProgramElement::Align(ref mut value) => vec![value],
+            ProgramElement::Data(_) => continue
+        };
+        for param in params.iter_mut() {
+            qualify_ref(param, &scope, &seen, &totals);
+        }
+    }
+}
+
+/// A run of consecutive words in the output, from one `.org` (or the start of the program) to the next.
+struct Region {
+    start: u32,
+    end: u32,
+    /// The `.org` that started the region.
+    span: Option<Span>
+}
+
+/// Works out the address of every element, following `.org` and `.align`. Their operands may only use constants
+/// that don't depend on labels, given in `constants`.
+fn place(elems: &Vec<SourceElement>, constants: &HashMap<String, u16>, errors: &mut Vec<(Span, String)>) -> Vec<u16> {
+    let no_labels = HashMap::new();
+    let mut addresses = Vec::new();
+    let mut regions = vec![Region { start: 0, end: 0, span: None }];
+    let mut acc = 0u32;
+    let mut overflowed = false;
 
     for elem in elems {
-        if let &ProgramElement::Label(ref s) = elem {
-            map.insert(s.clone(), acc);
+        match elem.elem {
+            ProgramElement::Org(ref p) | ProgramElement::Align(ref p) => {
+                let value = match evaluate(p, &no_labels, constants) {
+                    Ok(v) => v as u32,
+                    Err(EvalError::UndefinedLabel(_)) => {
+                        errors.push((elem.operands[0], "addresses in `.org` and `.align` can't depend on labels".to_string()));
+                        addresses.push(acc as u16);
+                        continue;
+                    },
+                    Err(e) => {
+                        errors.push((elem.operands[0], e.message()));
+                        addresses.push(acc as u16);
+                        continue;
+                    }
+                };
+                if let ProgramElement::Org(_) = elem.elem {
+                    if value > 32767 {
+                        errors.push((elem.operands[0], format!("x{:04x} is outside memory, which ends at x7fff", value)));
+                        addresses.push(acc as u16);
+                        continue;
+                    }
+                    regions.last_mut().unwrap().end = acc;
+                    regions.push(Region { start: value, end: value, span: Some(elem.span) });
+                    acc = value;
+                } else if value == 0 {
+                    errors.push((elem.operands[0], "can't align to 0 words".to_string()));
+                } else {
+                    acc = (acc + value - 1) / value * value;
+                }
+            },
+            _ => {}
+        }
+        addresses.push(cmp::min(acc, 32767) as u16);
+        acc += elem.elem.size() as u32;
+        if acc > 32768 && !overflowed {
+            errors.push((elem.span, "the program doesn't fit in the 32768 words of memory".to_string()));
+            overflowed = true;
+        }
+    }
+    regions.last_mut().unwrap().end = acc;
+
+    regions.retain(|r| r.start < r.end);
+    for (i, region) in regions.iter().enumerate() {
+        if let Some(other) = regions[..i].iter().find(|r| r.start < region.end && region.start < r.end) {
+            let (start, end) = (cmp::max(region.start, other.start), cmp::min(region.end, other.end));
+            errors.push((region.span.unwrap(), format!("the code after this overlaps x{:04x}..x{:04x}, which is already in use",
+                                                       start, end)));
+        }
+    }
+
+    return addresses;
+}
+
+/// Duplicate definitions are reported, and the span of each is paired with the first definition in `first_defined`.
+fn locate_labels(elems: &Vec<SourceElement>, addresses: &[u16], errors: &mut Vec<(Span, String)>,
+                 first_defined: &mut Vec<(Span, Span)>) -> HashMap<String, u16> {
+    let mut map: HashMap<String, u16> = HashMap::new();
+    let mut defined_at: HashMap<String, Span> = HashMap::new();
+
+    for (elem, &adr) in elems.iter().zip(addresses) {
+        if let ProgramElement::Label(ref s) = elem.elem {
+            if let Some(&first) = defined_at.get(s) {
+                errors.push((elem.span, format!("label `{}` is defined more than once", s)));
+                first_defined.push((elem.span, first));
+            } else {
+                defined_at.insert(s.clone(), elem.span);
+                map.insert(s.clone(), adr);
+            }
         }
-        acc += elem.size();
     }
 
     return map;
 }
 
-fn reify_label(param: &Parameter, labels: &HashMap<String, u16>) -> Result<Parameter, String> {
+enum EvalError {
+    UndefinedLabel(String),
+    UndefinedConstant(String),
+    Overflow,
+    OutOfRange(i64)
+}
+
+impl EvalError {
+    fn message(&self) -> String {
+        match *self {
+            EvalError::UndefinedLabel(ref s) if s.starts_with('.') => format!("local label `{}` is used before any global label", s),
+            EvalError::UndefinedLabel(ref s) => match numeric_ref(s) {
+                Some((num, "f")) => format!("no `{}:` label after this", num),
+                Some((num, _)) => format!("no `{}:` label before this", num),
+                None => format!("undefined label `{}`", s)
+            },
+            EvalError::UndefinedConstant(ref s) => format!("undefined constant `{}`", s),
+            EvalError::Overflow => "expression overflows".to_string(),
+            EvalError::OutOfRange(v) => format!("expression evaluates to {}, which is outside 0..32767", v)
+        }
+    }
+}
+
+fn evaluate_expr(expr: &Expr, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> Result<i64, EvalError> {
+    let (op, a, b) = match *expr {
+        Expr::Term(ref p) => return evaluate(p, labels, constants).map(|v| v as i64),
+        Expr::Binary(op, ref a, ref b) => (op, evaluate_expr(a, labels, constants)?, evaluate_expr(b, labels, constants)?)
+    };
+    let res = match op {
+        BinOp::Add => a.checked_add(b),
+        BinOp::Sub => a.checked_sub(b),
+        BinOp::Mul => a.checked_mul(b),
+        BinOp::Shl if b >= 0 && b < 48 => a.checked_mul(1 << b),
+        BinOp::Shl => None,
+        BinOp::Shr if b >= 0 => Some(if b < 64 { a >> b } else if a < 0 { -1 } else { 0 }),
+        BinOp::Shr => None,
+        BinOp::And => Some(a & b),
+        BinOp::Or => Some(a | b)
+    };
+    res.ok_or(EvalError::Overflow)
+}
+
+/// Works out the value of an operand. Expressions must end up in 0..32767.
+fn evaluate(param: &Parameter, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> Result<u16, EvalError> {
     match *param {
-        Parameter::Label(ref s) => match labels.get(s) {
-            Some(adr) => Ok(Parameter::Literal(*adr)),
-            None => Err(s.clone())
+        Parameter::Literal(x) => Ok(x),
+        Parameter::Label(ref s) => labels.get(s).cloned().ok_or_else(|| EvalError::UndefinedLabel(s.clone())),
+        Parameter::Constant(ref s) => constants.get(s).cloned().ok_or_else(|| EvalError::UndefinedConstant(s.clone())),
+        Parameter::Expr(ref e) => {
+            let v = evaluate_expr(e, labels, constants)?;
+            if v < 0 || v > 32767 {
+                return Err(EvalError::OutOfRange(v));
+            }
+            Ok(v as u16)
+        },
+        Parameter::Register(_) => unreachable!("registers have no value at assembly time")
+    }
+}
+
+/// Works out the value of every `.equ` constant. Constants may refer to labels and to each other, in any order.
+/// Values in `defines` replace the definitions in the source. Duplicates are reported like in `locate_labels`.
+fn resolve_constants(elems: &Vec<SourceElement>, labels: &HashMap<String, u16>, defines: &HashMap<String, u16>,
+                     errors: &mut Vec<(Span, String)>, first_defined: &mut Vec<(Span, Span)>) -> HashMap<String, u16> {
+    let mut values = defines.clone();
+    let mut pending: Vec<(&str, &Parameter, Span)> = Vec::new();
+    let mut defined_at: HashMap<&str, Span> = HashMap::new();
+
+    for elem in elems {
+        if let ProgramElement::Constant(ref name, ref value) = elem.elem {
+            if let Some(&first) = defined_at.get(name.as_str()) {
+                errors.push((elem.span, format!("constant `{}` is defined more than once", name)));
+                first_defined.push((elem.span, first));
+                continue;
+            }
+            defined_at.insert(name, elem.span);
+            if !defines.contains_key(name) {
+                pending.push((name, value, elem.operands[1]));
+            }
+        }
+    }
+
+    loop {
+        let before = pending.len();
+        pending.retain(|&(name, value, _)| {
+            match evaluate(value, labels, &values) {
+                Ok(v) => {
+                    values.insert(name.to_string(), v);
+                    false
+                },
+                Err(_) => true
+            }
+        });
+        if pending.len() == before {
+            break;
         }
-        _ => Ok(param.clone())
+    }
+
+    for (name, value, span) in pending {
+        match evaluate(value, labels, &values) {
+            Err(EvalError::UndefinedConstant(ref c)) if defined_at.contains_key(c.as_str()) =>
+                errors.push((span, format!("constant `{}` depends on itself", name))),
+            Err(e) => errors.push((span, e.message())),
+            Ok(_) => unreachable!()
+        }
+    }
+
+    return values;
+}
+
+/// Reports every operand that can't be evaluated, or whose value isn't a valid literal. Values above 32767 would be
+/// read as registers (or be invalid), so they are only accepted with `allow_raw`. `.word` data isn't an operand
+/// and may hold any word, which is how `syndis` writes words that don't decode.
+fn check_operands(elems: &Vec<SourceElement>, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>,
+                  allow_raw: bool, errors: &mut Vec<(Span, String)>) {
+    // Constants that are defined but couldn't be resolved have already been reported
+    let defined: Vec<&String> = elems.iter().filter_map(|e| match e.elem {
+        ProgramElement::Constant(ref name, _) => Some(name),
+        _ => None
+    }).collect();
+
+    for elem in elems {
+        if let ProgramElement::Instruction(ref instr) = elem.elem {
+            for (param, span) in instr.operands().iter().zip(elem.operands.iter()) {
+                if let Parameter::Register(_) = *param {
+                    continue;
+                }
+                match evaluate(param, labels, constants) {
+                    Err(EvalError::UndefinedConstant(ref s)) if defined.contains(&s) => {},
+                    Err(e) => errors.push((*span, e.message())),
+                    Ok(v) if v > 32767 && !allow_raw => errors.push((*span, match Register::from_word(v) {
+                        Some(r) => format!("{} is outside 0..32767, so it would be read as register {}", v, r),
+                        None => format!("{} is outside 0..32767, so it isn't a valid operand", v)
+                    })),
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+fn reify_label(param: &Parameter, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) -> Parameter {
+    if let Parameter::Register(_) = *param {
+        return param.clone();
+    }
+    match evaluate(param, labels, constants) {
+        Ok(v) => Parameter::Literal(v),
+        Err(_) => param.clone()
     }
 }
 
-fn reify_labels(elems: &mut Vec<ProgramElement>, labels: &HashMap<String, u16>) -> Result<(), String> {
+/// Replaces every label, constant and expression operand with its value. Operands that can't be evaluated are left alone;
+/// `check_operands` reports them.
+fn reify_labels(elems: &mut Vec<SourceElement>, labels: &HashMap<String, u16>, constants: &HashMap<String, u16>) {
     for elem in elems {
-        if let &mut ProgramElement::Instruction(ref mut instr) = elem {
+        if let ProgramElement::Instruction(ref mut instr) = elem.elem {
             match instr {
-                &mut Instruction::Set(_, ref mut b) => *b = reify_label(&b, labels)?,
-                &mut Instruction::Push(ref mut a) => *a = reify_label(&a, labels)?,
-                &mut Instruction::Eq(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Gt(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Jmp(ref mut a) => *a = reify_label(&a, labels)?,
-                &mut Instruction::Jt(ref mut a, ref mut b) => {*a = reify_label(&a, labels)?; *b = reify_label(&b, labels)?},
-                &mut Instruction::Jf(ref mut a, ref mut b) => {*a = reify_label(&a, labels)?; *b = reify_label(&b, labels)?},
-                &mut Instruction::Add(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Mult(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Mod(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::And(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Or(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels)?; *c = reify_label(&c, labels)?},
-                &mut Instruction::Not(_, ref mut b) => *b = reify_label(&b, labels)?,
-                &mut Instruction::Rmem(_, ref mut b) => *b = reify_label(&b, labels)?,
-                &mut Instruction::Wmem(ref mut a, ref mut b) => {*a = reify_label(&a, labels)?; *b = reify_label(&b, labels)?},
-                &mut Instruction::Call(ref mut a) => *a = reify_label(&a, labels)?,
-                &mut Instruction::Out(ref mut a) => *a = reify_label(&a, labels)?,
+                &mut Instruction::Set(_, ref mut b) => *b = reify_label(&b, labels, constants),
+                &mut Instruction::Push(ref mut a) => *a = reify_label(&a, labels, constants),
+                &mut Instruction::Eq(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Gt(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Jmp(ref mut a) => *a = reify_label(&a, labels, constants),
+                &mut Instruction::Jt(ref mut a, ref mut b) => {*a = reify_label(&a, labels, constants); *b = reify_label(&b, labels, constants)},
+                &mut Instruction::Jf(ref mut a, ref mut b) => {*a = reify_label(&a, labels, constants); *b = reify_label(&b, labels, constants)},
+                &mut Instruction::Add(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Mult(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Mod(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::And(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Or(_, ref mut b, ref mut c) => {*b = reify_label(&b, labels, constants); *c = reify_label(&c, labels, constants)},
+                &mut Instruction::Not(_, ref mut b) => *b = reify_label(&b, labels, constants),
+                &mut Instruction::Rmem(_, ref mut b) => *b = reify_label(&b, labels, constants),
+                &mut Instruction::Wmem(ref mut a, ref mut b) => {*a = reify_label(&a, labels, constants); *b = reify_label(&b, labels, constants)},
+                &mut Instruction::Call(ref mut a) => *a = reify_label(&a, labels, constants),
+                &mut Instruction::Out(ref mut a) => *a = reify_label(&a, labels, constants),
                 _ => {}
             };
         }
     }
-    Ok(())
 }
 
-fn write_program(out: &mut Write, prg: &Vec<ProgramElement>) {
-    for elem in prg {
-        match *elem {
-            ProgramElement::Instruction(ref instr) => instr.write(out),
-            ProgramElement::Data(ref d) => {
-                for v in d {
-                    out.write_u16::<LittleEndian>(*v).expect("Unable to write to file");
+#[derive(Debug)]
+pub enum AssemblerError {
+    ParserError(String),
+    /// Every problem found in an otherwise well-formed program.
+    Diagnostics(Vec<Diagnostic>)
+}
+
+impl fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AssemblerError::ParserError(ref e) => write!(f, "{}", e),
+            AssemblerError::Diagnostics(ref diags) => {
+                for (i, d) in diags.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", d)?;
                 }
-            },
-            _ => {}
+                Ok(())
+            }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum AssemblerError {
-    ParserError(String),
-    LabelResolveError(String)
+/// A fully resolved program, ready to be written out.
+pub struct Assembly {
+    elements: Vec<SourceElement>,
+    /// The address of every element.
+    addresses: Vec<u16>,
+    labels: HashMap<String, u16>,
+    map: SourceMap
 }
 
-pub fn assemble(out: &mut Write, src: &str) -> Result<(), AssemblerError> {
-    let mut res = ::parser::parse(src).map_err(|e| AssemblerError::ParserError(e.to_string()))?;
-    let labels = locate_labels(&res);
-    reify_labels(&mut res, &labels).map_err(|x| AssemblerError::LabelResolveError(x))?;
-    write_program(out, &res);
+impl Assembly {
+    pub fn labels(&self) -> &HashMap<String, u16> {
+        &self.labels
+    }
+
+    /// Every word of the program, from address 0 up to the last word used. Gaps left by `.org` and `.align` are zero.
+    pub fn image(&self) -> Vec<u16> {
+        let mut image = Vec::new();
+        for (elem, &adr) in self.elements.iter().zip(&self.addresses) {
+            let words = elem.elem.words();
+            let end = adr as usize + words.len();
+            if image.len() < end {
+                image.resize(end, 0);
+            }
+            image[(adr as usize)..end].copy_from_slice(&words);
+        }
+        return image;
+    }
+
+    pub fn write(&self, out: &mut Write) {
+        for v in self.image() {
+            out.write_u16::<LittleEndian>(v).expect("Unable to write to file");
+        }
+    }
+
+    /// Writes every line of the source next to the address it was assembled at and the words it produced.
+    /// Lines that expand into other code (macro calls, includes and pseudo-instructions) are followed by
+    /// one line for every element they produced.
+    pub fn write_listing(&self, out: &mut Write) -> io::Result<()> {
+        let src = &self.map.file(0).src;
+        // Where every line starts, so that finding an element's line doesn't rescan the source
+        let line_starts: Vec<usize> = Some(0).into_iter().chain(src.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        let mut lines: BTreeMap<usize, Vec<(u16, &SourceElement)>> = BTreeMap::new();
+        for (elem, &adr) in self.elements.iter().zip(&self.addresses) {
+            if let ProgramElement::Constant(..) = elem.elem {
+                continue;
+            }
+            let line = match line_starts.binary_search(&self.map.main_span(elem.span).start) {
+                Ok(i) => i + 1,
+                Err(i) => i
+            };
+            lines.entry(line).or_insert(Vec::new()).push((adr, elem));
+        }
+
+        writeln!(out, "ADDR   WORDS                 SOURCE")?;
+        for (i, text) in src.lines().enumerate() {
+            let elems = match lines.get(&(i + 1)) {
+                Some(elems) => elems,
+                None => {
+                    writeln!(out, "{:28}{}", "", text)?;
+                    continue;
+                }
+            };
+
+            let adr = elems[0].0;
+            if elems.iter().all(|&(_, e)| !e.generated && self.map.main_span(e.span) == e.span) {
+                let words: Vec<u16> = elems.iter().flat_map(|&(_, e)| e.elem.words()).collect();
+                write_listing_words(out, adr, &words, text)?;
+                continue;
+            }
+
+            writeln!(out, "x{:04x}  {:20}  {}", adr, "", text)?;
+            let indent: String = text.chars().take_while(|c| c.is_whitespace()).collect();
+            for &(adr, elem) in elems {
+                let desc = match elem.elem {
+                    ProgramElement::Label(ref s) => format!("{}:", s),
+                    ProgramElement::Instruction(ref instr) => format!("  {}", instr),
+                    ProgramElement::Data(_) => "  .word ...".to_string(),
+                    ProgramElement::Org(ref p) => format!("  .org {}", p),
+                    ProgramElement::Align(ref p) => format!("  .align {}", p),
+                    ProgramElement::Constant(..) => unreachable!()
+                };
+                write_listing_words(out, adr, &elem.elem.words(), &format!("{}  {}", indent, desc))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `words` four to a row, with `text` next to the first row.
+fn write_listing_words(out: &mut Write, adr: u16, words: &[u16], text: &str) -> io::Result<()> {
+    let mut rows = words.chunks(4);
+    let first: Vec<String> = rows.next().unwrap_or(&[]).iter().map(|w| format!("{:04x}", w)).collect();
+    writeln!(out, "x{:04x}  {:<20}  {}", adr, first.join(" "), text)?;
+    for (j, row) in rows.enumerate() {
+        let row: Vec<String> = row.iter().map(|w| format!("{:04x}", w)).collect();
+        writeln!(out, "x{:04x}  {}", adr as usize + (j + 1) * 4, row.join(" "))?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Reports register aliases named like a constant, since the alias would silently replace the constant
+/// wherever it's in scope.
+fn check_aliases(aliases: &[(Span, String)], elems: &Vec<SourceElement>, defines: &HashMap<String, u16>,
+                 errors: &mut Vec<(Span, String)>) {
+    for &(span, ref name) in aliases {
+        let is_constant = defines.contains_key(name) || elems.iter().any(|e| match e.elem {
+            ProgramElement::Constant(ref c, _) => c == name,
+            _ => false
+        });
+        if is_constant {
+            errors.push((span, format!("alias `{}` has the same name as a constant", name)));
+        }
+    }
+}
+
+pub struct Assembler {
+    defines: HashMap<String, u16>,
+    include_paths: Vec<PathBuf>,
+    allow_raw_words: bool
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        return Assembler {
+            defines: HashMap::new(),
+            include_paths: Vec::new(),
+            allow_raw_words: false
+        };
+    }
+
+    /// Accepts operands from 32768 to 65535 and emits them as they are, even though the VM reads them as registers.
+    pub fn set_allow_raw_words(&mut self, allow: bool) {
+        self.allow_raw_words = allow;
+    }
+
+    /// Adds a directory to search for `.include` and `.incbin` files that aren't next to the including file.
+    pub fn add_include_path<P: AsRef<Path>>(&mut self, path: P) {
+        self.include_paths.push(path.as_ref().to_path_buf());
+    }
+
+    /// Sets the constant `name`, replacing any `.equ` for it in the source.
+    pub fn define(&mut self, name: &str, value: u16) {
+        self.defines.insert(name.to_string(), value);
+    }
+
+    /// Assembles `src`, which was read from `file_name`. Included files are looked up relative to it.
+    pub fn assemble(&self, file_name: &str, src: &str) -> Result<Assembly, AssemblerError> {
+        let mut map = SourceMap::new();
+        let file = map.add_file(file_name, src.to_string());
+        let (statements, mut errors) = ::parser::parse(file, src, &::parser::Aliases::new())
+            .map_err(|e| AssemblerError::ParserError(format!("{}:\n{}", file_name, e)))?;
+        let statements = ::includes::resolve(statements, file_name, &self.include_paths, &mut map, &mut errors)?;
+        let statements = ::macros::expand(statements, &mut map, &mut errors);
+        let aliases: Vec<(Span, String)> = statements.iter().filter_map(|s| match *s {
+            Statement::Alias { span, ref name } => Some((span, name.clone())),
+            _ => None
+        }).collect();
+        let mut res = ::pseudo::lower(statements, &mut errors);
+
+        qualify_labels(&mut res, &mut errors);
+        // `.org` and `.align` have to be placed before labels have addresses, so they only see the constants that
+        // don't need any. Errors in the others are reported once everything is placed.
+        let early = resolve_constants(&res, &HashMap::new(), &self.defines, &mut Vec::new(), &mut Vec::new());
+        let addresses = place(&res, &early, &mut errors);
+        let mut first_defined = Vec::new();
+        let labels = locate_labels(&res, &addresses, &mut errors, &mut first_defined);
+        let constants = resolve_constants(&res, &labels, &self.defines, &mut errors, &mut first_defined);
+        check_operands(&res, &labels, &constants, self.allow_raw_words, &mut errors);
+        check_aliases(&aliases, &res, &self.defines, &mut errors);
+        if !errors.is_empty() {
+            errors.sort_by_key(|&(span, _)| map.main_span(span).start);
+            return Err(AssemblerError::Diagnostics(errors.into_iter()
+                .map(|(span, msg)| {
+                    let mut diag = Diagnostic::new(&map, span, msg);
+                    for &(_, first) in first_defined.iter().filter(|&&(dup, _)| dup == span) {
+                        diag.notes.push(Diagnostic::new(&map, first, "first defined here".to_string()));
+                    }
+                    diag
+                })
+                .collect()));
+        }
+
+        reify_labels(&mut res, &labels, &constants);
+        return Ok(Assembly {
+            elements: res,
+            addresses: addresses,
+            // Numeric labels are only meaningful next to their references
+            labels: labels.into_iter().filter(|&(ref name, _)| !name.contains('#')).collect(),
+            map: map
+        });
+    }
+}
+
+/// Assembles `src` with no predefined constants or include paths.
+pub fn assemble_program(file_name: &str, src: &str) -> Result<Assembly, AssemblerError> {
+    Assembler::new().assemble(file_name, src)
+}
+
+/// Assembles `src` into `out`, returning the address of every label.
+pub fn assemble(out: &mut Write, file_name: &str, src: &str) -> Result<HashMap<String, u16>, AssemblerError> {
+    let assembly = assemble_program(file_name, src)?;
+    assembly.write(out);
+    Ok(assembly.labels)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+
+    /// Assembles `src`, which has to be valid, into a memory image.
+    pub fn image(src: &str) -> Vec<u16> {
+        match assemble_program("test.synasm", src) {
+            Ok(assembly) => assembly.image(),
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    /// The messages of the errors in `src`, which has to parse.
+    pub fn errors(src: &str) -> Vec<String> {
+        match assemble_program("test.synasm", src) {
+            Err(AssemblerError::Diagnostics(d)) => d.into_iter().map(|d| d.message).collect(),
+            Err(e) => panic!("{}", e),
+            Ok(_) => Vec::new()
+        }
+    }
+
+    #[test]
+    fn qualifies_local_labels() {
+        assert_eq!(image("start:\n.loop: jmp :.loop\nother:\n.loop: jmp :.loop\njmp :start.loop"), vec![6, 0, 6, 2, 6, 0]);
+        assert_eq!(errors("jmp :.x"), vec!["local label `.x` is used before any global label"]);
+    }
+
+    #[test]
+    fn resolves_numeric_labels() {
+        assert_eq!(image("1: out 1\njmp :1f\njmp :1b\n1: halt"), vec![19, 1, 6, 6, 6, 0, 0]);
+        assert_eq!(errors("jmp :1f"), vec!["no `1:` label after this"]);
+        assert_eq!(errors("1: jmp :2b"), vec!["no `2:` label before this"]);
+    }
+
+    #[test]
+    fn fills_gaps_between_org_regions() {
+        assert_eq!(image(".org 4\nout 1"), vec![0, 0, 0, 0, 19, 1]);
+        assert_eq!(image(".org 4\nhalt\n.org 0\nout 1"), vec![19, 1, 0, 0, 0]);
+        assert_eq!(image(".equ BASE 3\n.org BASE + 1\nstart: jmp :start"), vec![0, 0, 0, 0, 6, 4]);
+    }
+
+    #[test]
+    fn aligns() {
+        assert_eq!(image("out 1\n.align 4\nhalt"), vec![19, 1, 0, 0, 0]);
+        assert_eq!(image("out 1\nout 2\n.align 4\nhalt"), vec![19, 1, 19, 2, 0]);
+        assert_eq!(errors(".align 0"), vec!["can't align to 0 words"]);
+    }
+
+    #[test]
+    fn reports_overlapping_regions() {
+        assert_eq!(errors(".org 2\nout 1\n.org 3\nhalt"), vec!["the code after this overlaps x0003..x0004, which is already in use"]);
+        assert_eq!(errors("a: .org :a"), vec!["addresses in `.org` and `.align` can't depend on labels"]);
+    }
+
+    #[test]
+    fn rejects_literals_outside_a_word() {
+        assert_eq!(errors("out 70000"), vec!["literal 70000 is out of range (0-65535)"]);
+        assert_eq!(errors("out 32768"), vec!["32768 is outside 0..32767, so it would be read as register $0"]);
+        assert_eq!(errors("out xffff"), vec!["65535 is outside 0..32767, so it isn't a valid operand"]);
+        assert_eq!(errors(".equ BIG 32775\nout BIG"), vec!["32775 is outside 0..32767, so it would be read as register $7"]);
+        assert_eq!(errors("out '\u{8000}'"), vec!["character '\u{8000}' is outside 0..32767"]);
+    }
+
+    #[test]
+    fn allows_raw_words_when_asked() {
+        let mut asm = Assembler::new();
+        asm.set_allow_raw_words(true);
+        assert_eq!(asm.assemble("test.synasm", "out 32768\nout xffff").unwrap().image(), vec![19, 32768, 19, 65535]);
+        assert!(asm.assemble("test.synasm", "out 70000").is_err());
+        assert!(asm.assemble("test.synasm", "out '\u{8000}'").is_err());
+    }
+
+    #[test]
+    fn emits_any_word_as_data() {
+        assert_eq!(image(".word 32768, xffff"), vec![32768, 65535]);
+        assert_eq!(errors(".word 65536"), vec!["literal 65536 is out of range (0-65535)"]);
+    }
+
+    #[test]
+    fn emits_data_directives() {
+        assert_eq!(image(".word 1, x20, 'a'"), vec![1, 32, 97]);
+        assert_eq!(image(".zero 3\nhalt"), vec![0, 0, 0, 0]);
+        assert_eq!(image(".zero 0\nout 1"), vec![19, 1]);
+        assert_eq!(image(".string \"hi\\n\""), vec![104, 105, 10, 0]);
+        assert_eq!(image(".string \"\""), vec![0]);
+        assert_eq!(image(".pstring \"hi\\\"\""), vec![3, 104, 105, 34]);
+        assert_eq!(image(".pstring \"\""), vec![0]);
+    }
+
+    #[test]
+    fn points_labels_at_data() {
+        assert_eq!(image("rmem $0 :msg\nout $0\nout :end-:msg\nhalt\nmsg: .pstring \"ok\"\nend: .zero 1"),
+                   vec![15, 32768, 8, 19, 32768, 19, 3, 0, 2, 111, 107, 0]);
+    }
+
+    #[test]
+    fn lists_addresses_and_words() {
+        let assembly = assemble_program("test.synasm", "start: set $0 1\n    neg $1 $0   ; two instructions\n\n\
+                                                         .org 12\n    .word 1, 2, 3, 4, 5\n    halt\n").unwrap();
+        let mut out = Vec::new();
+        assembly.write_listing(&mut out).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        assert_eq!(listing.lines().map(|l| l.trim_right()).collect::<Vec<_>>(), vec![
+            "ADDR   WORDS                 SOURCE",
+            "x0000  0001 8000 0001        start: set $0 1",
+            "x0003                            neg $1 $0   ; two instructions",
+            "x0003  000e 8001 8000                not $1 $0",
+            "x0006  0009 8001 8001 0001           add $1 $1 1",
+            "",
+            "x000c                        .org 12",
+            "x000c  0001 0002 0003 0004       .word 1, 2, 3, 4, 5",
+            "x0010  0005",
+            "x0011  0000                      halt"
+        ]);
+
+        // The listed words are the ones in the binary, which also has the padding `.org` left
+        let mut binary = Vec::new();
+        assembly.write(&mut binary);
+        let words: Vec<u16> = binary.chunks(2).map(LittleEndian::read_u16).collect();
+        assert_eq!(words, vec![1, 32768, 1, 14, 32769, 32768, 9, 32769, 32769, 1, 0, 0, 1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn evaluates_with_precedence() {
+        assert_eq!(image("out 1 + 2 * 3"), vec![19, 7]);
+        assert_eq!(image("out (1 + 2) * 3"), vec![19, 9]);
+        assert_eq!(image("out 1 << 2 + 1"), vec![19, 8]);
+        assert_eq!(image("out 6 & 3 | 8"), vec![19, 10]);
+        assert_eq!(image("out 1 | 6 & 3"), vec![19, 3]);
+        assert_eq!(image("out 1 << 2 & 4"), vec![19, 4]);
+        assert_eq!(image("out 0x100 >> 4 - 1"), vec![19, 32]);
+    }
+
+    #[test]
+    fn subtracts_within_range() {
+        assert_eq!(image(".equ N 10\nout N-1"), vec![19, 9]);
+        assert_eq!(image("out 1 - 2 + 5"), vec![19, 4]);
+        assert_eq!(image("out (1 - 2) & 32767"), vec![19, 32767]);
+        assert_eq!(errors("out 1 - 2"), vec!["expression evaluates to -1, which is outside 0..32767"]);
+        assert_eq!(errors("out 16384 * 2"), vec!["expression evaluates to 32768, which is outside 0..32767"]);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(image("out 1 << 14"), vec![19, 16384]);
+        assert_eq!(image("out 1 << 20 >> 10"), vec![19, 1024]);
+        assert_eq!(errors("out 1 << 15"), vec!["expression evaluates to 32768, which is outside 0..32767"]);
+    }
+
+    #[test]
+    fn computes_with_labels() {
+        assert_eq!(image("start: out :end-:start\nset $0 :end+1\nend: halt"), vec![19, 5, 1, 32768, 6, 0]);
+        assert_eq!(image(".equ LEN :msg_end - :msg\nout LEN\nmsg: .string \"hi\"\nmsg_end:"), vec![19, 3, 104, 105, 0]);
+        assert_eq!(errors("out :nowhere+1"), vec!["undefined label `nowhere`"]);
+    }
+}
@@ -2,10 +2,30 @@ extern crate clap;
 extern crate rustacor;
 
 use rustacor::assembler;
+use rustacor::symbols::SymbolTable;
 
 use clap::*;
 use std::fs::{File};
 use std::io::{Read};
+use std::process;
+
+/// Parses `NAME=VALUE`, where the value is decimal, `xHEX` or `0xHEX` like in the source.
+fn parse_define(s: &str) -> Option<(&str, u16)> {
+    let mut parts = s.splitn(2, '=');
+    let name = parts.next().unwrap();
+    let value = parts.next()?;
+    let value = if value.starts_with("0x") {
+        u16::from_str_radix(&value[2..], 16).ok()?
+    } else if value.starts_with('x') {
+        u16::from_str_radix(&value[1..], 16).ok()?
+    } else {
+        value.parse::<u16>().ok()?
+    };
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, value))
+}
 
 fn main() {
     let matches = App::new("synasm")
@@ -15,6 +35,33 @@ fn main() {
             .takes_value(true)
             .value_name("FILE")
             .required(true))
+        .arg(Arg::with_name("map")
+            .long("map")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the address of every label to a map file, for synvm --symbols"))
+        .arg(Arg::with_name("listing")
+            .long("listing")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write a listing of every source line with its address and encoded words"))
+        .arg(Arg::with_name("define")
+            .short("D")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NAME=VALUE")
+            .help("Set a constant, overriding its .equ in the source"))
+        .arg(Arg::with_name("include")
+            .short("I")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("DIR")
+            .help("Search this directory for .include and .incbin files"))
+        .arg(Arg::with_name("allow-raw-words")
+            .long("allow-raw-words")
+            .help("Accept operands from 32768 to 65535, which the VM reads as registers"))
         .arg(Arg::with_name("input")
             .required(true)
             .index(1))
@@ -26,9 +73,38 @@ fn main() {
         f.read_to_string(&mut src).expect("Unable to read file");
 
         let mut o = File::create(output_name).expect("Unable to open output file");
-        assembler::assemble(&mut o, &src).map_err(|e| format!("While assembling code: {}", match e {
-            assembler::AssemblerError::LabelResolveError(ref s) => format!("Unknown label :{}", s),
-            assembler::AssemblerError::ParserError(e) => format!("\n{}", e)
-        })).unwrap();
+        let mut asm = assembler::Assembler::new();
+        asm.set_allow_raw_words(matches.is_present("allow-raw-words"));
+        for dir in matches.values_of("include").into_iter().flat_map(|v| v) {
+            asm.add_include_path(dir);
+        }
+        for define in matches.values_of("define").into_iter().flat_map(|v| v) {
+            match parse_define(define) {
+                Some((name, value)) => asm.define(name, value),
+                None => {
+                    eprintln!("Invalid definition {}, expected NAME=VALUE", define);
+                    process::exit(1);
+                }
+            }
+        }
+
+        let assembly = match asm.assemble(file_name, &src) {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+        assembly.write(&mut o);
+
+        if let Some(listing_name) = matches.value_of("listing") {
+            let mut l = File::create(listing_name).expect("Unable to open listing file");
+            assembly.write_listing(&mut l).expect("Unable to write listing file");
+        }
+
+        if let Some(map_name) = matches.value_of("map") {
+            let mut m = File::create(map_name).expect("Unable to open map file");
+            SymbolTable::from_labels(assembly.labels()).write(&mut m).expect("Unable to write map file");
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+extern crate clap;
+extern crate rustacor;
+
+use rustacor::analysis::Cfg;
+use rustacor::disassembler;
+
+use clap::*;
+use std::fs::{File};
+use std::io::{Write, stdout};
+
+fn main() {
+    let matches = App::new("syndis")
+        .arg(Arg::with_name("output")
+            .short("-o")
+            .long("out")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("addresses")
+            .long("addresses")
+            .help("Annotate each line with its address"))
+        .arg(Arg::with_name("cfg")
+            .long("cfg")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Write the control-flow graph to FILE in Graphviz DOT format"))
+        .arg(Arg::with_name("input")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    if let Some(file_name) = matches.value_of("input") {
+        let mut f = File::open(file_name).expect("Unable to open file");
+        let words = disassembler::read_words(&mut f);
+        let elems = disassembler::disassemble(&words);
+
+        if let Some(cfg_name) = matches.value_of("cfg") {
+            let mut f = File::create(cfg_name).expect("Unable to open CFG file");
+            Cfg::new(&words).write_dot(&mut f).expect("Unable to write CFG");
+        }
+
+        let mut o: Box<Write> = match matches.value_of("output") {
+            Some(output_name) => Box::new(File::create(output_name).expect("Unable to open output file")),
+            None => Box::new(stdout())
+        };
+        disassembler::write_source(&mut o, &elems, matches.is_present("addresses")).expect("Unable to write output");
+    }
+}
@@ -0,0 +1,38 @@
+extern crate clap;
+extern crate rustacor;
+
+use rustacor::disassembler;
+use rustacor::translate;
+
+use clap::*;
+use std::fs::{File};
+use std::io::{Write, stdout};
+use std::process;
+
+fn main() {
+    let matches = App::new("syntrans")
+        .arg(Arg::with_name("output")
+            .short("-o")
+            .long("out")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("input")
+            .required(true)
+            .index(1))
+        .get_matches();
+
+    if let Some(file_name) = matches.value_of("input") {
+        let mut f = File::open(file_name).expect("Unable to open file");
+        let words = disassembler::read_words(&mut f);
+        if words.len() > 32768 {
+            eprintln!("{} has {} words, but the VM only has 32768 words of memory", file_name, words.len());
+            process::exit(1);
+        }
+
+        let mut o: Box<Write> = match matches.value_of("output") {
+            Some(output_name) => Box::new(File::create(output_name).expect("Unable to open output file")),
+            None => Box::new(stdout())
+        };
+        translate::translate(&mut o, &words, file_name).expect("Unable to write output");
+    }
+}
@@ -3,13 +3,408 @@ extern crate rustacor;
 extern crate byteorder;
 
 use rustacor::assembler;
+use rustacor::symbols::SymbolTable;
+use rustacor::trace;
 use rustacor::vm;
 
-use clap::{App, Arg, ArgGroup};
+use clap::{App, Arg, ArgGroup, ArgMatches};
 use std::char;
 use std::error::Error;
 use std::fs::File;
-use std::io::{Read, stdin};
+use std::io::{BufWriter, Read, Write, stdin, stdout};
+
+const DEBUG_HELP: &'static str = "\
+Commands:
+  s, step [n]          Execute n instructions (default 1)
+  c, continue          Run until a breakpoint or halt
+  bs, back [n]         Undo n instructions (default 1)
+  rc, reverse-continue Run backwards until a breakpoint or the start of the history
+  ww, whowrote <loc>   Go back to the last instruction that wrote a memory word or register ($0-$7)
+  b, break <loc>       Set a breakpoint at an address (1234, x4d2) or label (main, :main)
+  d, delete <loc>      Remove a breakpoint
+  bl, breakpoints      List breakpoints
+  w, watch <loc>[-<loc>] [read|write|change] [==v|!=v|<v|>v] [log]
+                       Stop when rmem/wmem reads or writes a memory range (default write), optionally only
+                       if the value read or written passes the test. With log, print the access and keep running
+  wd, unwatch <n>      Remove watchpoint n
+  wl, watchpoints      List watchpoints
+  r, regs              Show registers and pc
+  st, stack            Show the stack, top last
+  m, mem <loc> [n]     Show n words of memory (default 16)
+  save <file>          Save a snapshot of the VM state, resumable with --resume
+  q, quit              Exit
+An empty line repeats the previous command.";
+
+fn parse_location(s: &str, symbols: &SymbolTable) -> Result<u16, String> {
+    let label = if s.starts_with(':') { &s[1..] } else { s };
+    if let Some(adr) = symbols.get(label) {
+        return Ok(adr);
+    }
+
+    let v = if s.starts_with('x') {
+        u16::from_str_radix(&s[1..], 16)
+    } else {
+        s.parse::<u16>()
+    };
+    v.ok().filter(|v| *v < 32768).ok_or(format!("Unknown address or label {}", s))
+}
+
+fn parse_range(s: &str, symbols: &SymbolTable) -> Result<(u16, u16), String> {
+    let mut parts = s.splitn(2, '-');
+    let start = parse_location(parts.next().unwrap(), symbols)?;
+    let end = match parts.next() {
+        Some(e) => parse_location(e, symbols)?,
+        None => start
+    };
+    Ok((start, end))
+}
+
+fn parse_word(s: &str) -> Result<u16, String> {
+    let v = if s.starts_with('x') {
+        u16::from_str_radix(&s[1..], 16)
+    } else {
+        s.parse::<u16>()
+    };
+    v.map_err(|_| format!("Invalid value {}", s))
+}
+
+fn parse_watchpoint(args: &[&str], symbols: &SymbolTable) -> Result<vm::Watchpoint, String> {
+    let (start, end) = parse_range(args.get(0).ok_or("Missing argument")?, symbols)?;
+    let mut watchpoint = vm::Watchpoint {
+        start: start,
+        end: end,
+        kind: vm::WatchKind::Write,
+        condition: None,
+        log: false
+    };
+    for arg in &args[1..] {
+        match *arg {
+            "read" => watchpoint.kind = vm::WatchKind::Read,
+            "write" => watchpoint.kind = vm::WatchKind::Write,
+            "change" => watchpoint.kind = vm::WatchKind::Change,
+            "log" => watchpoint.log = true,
+            a if a.starts_with("==") => watchpoint.condition = Some(vm::Condition::Equal(parse_word(&a[2..])?)),
+            a if a.starts_with("!=") => watchpoint.condition = Some(vm::Condition::NotEqual(parse_word(&a[2..])?)),
+            a if a.starts_with('<') => watchpoint.condition = Some(vm::Condition::Less(parse_word(&a[1..])?)),
+            a if a.starts_with('>') => watchpoint.condition = Some(vm::Condition::Greater(parse_word(&a[1..])?)),
+            a => return Err(format!("Unknown watchpoint option {}", a))
+        }
+    }
+    if watchpoint.start > watchpoint.end {
+        return Err("The range of a watchpoint has to end after it starts".to_string());
+    }
+    Ok(watchpoint)
+}
+
+fn describe_watchpoint(w: &vm::Watchpoint, symbols: &SymbolTable) -> String {
+    let mut s = symbols.describe(w.start);
+    if w.end != w.start {
+        s += &format!("-{}", symbols.describe(w.end));
+    }
+    s += match w.kind {
+        vm::WatchKind::Read => " read",
+        vm::WatchKind::Write => " write",
+        vm::WatchKind::Change => " change"
+    };
+    match w.condition {
+        Some(vm::Condition::Equal(v)) => s += &format!(" =={}", v),
+        Some(vm::Condition::NotEqual(v)) => s += &format!(" !={}", v),
+        Some(vm::Condition::Less(v)) => s += &format!(" <{}", v),
+        Some(vm::Condition::Greater(v)) => s += &format!(" >{}", v),
+        None => {}
+    }
+    if w.log {
+        s += " log";
+    }
+    s
+}
+
+fn describe_hit(hit: &vm::WatchHit, symbols: &SymbolTable) -> String {
+    match hit.kind {
+        vm::WatchKind::Read => format!("Watchpoint {}: {} read by {} (instruction {}): {}",
+                                       hit.id, symbols.describe(hit.address), symbols.describe(hit.pc), hit.index, hit.value),
+        _ => format!("Watchpoint {}: {} written by {} (instruction {}): {} -> {}",
+                     hit.id, symbols.describe(hit.address), symbols.describe(hit.pc), hit.index, hit.old, hit.value)
+    }
+}
+
+fn trace_filter(matches: &ArgMatches, symbols: &SymbolTable) -> Result<trace::TraceFilter, String> {
+    let mut filter = trace::TraceFilter::new();
+    for r in matches.values_of("trace_range").into_iter().flat_map(|v| v) {
+        let (start, end) = parse_range(r, symbols)?;
+        filter.add_range(start, end);
+    }
+    for o in matches.values_of("trace_op").into_iter().flat_map(|v| v) {
+        filter.add_opcode(o);
+    }
+    if let Some(w) = matches.value_of("trace_window") {
+        let parts: Vec<&str> = w.splitn(2, '-').collect();
+        let bound = |s: &str| s.parse::<u64>().map_err(|_| format!("Invalid instruction count {}", s));
+        match parts.len() {
+            2 => filter.set_window(bound(parts[0])?, bound(parts[1])?),
+            _ => return Err(format!("Invalid trace window {}, expected <from>-<to>", w))
+        }
+    }
+    Ok(filter)
+}
+
+fn print_location(dbg: &vm::Debugger, symbols: &SymbolTable) {
+    let pc = dbg.vm().pc();
+    match dbg.vm().current_instruction() {
+        Ok(instr) => println!("{}: {}", symbols.describe(pc), instr),
+        Err(_) => println!("{}: <invalid instruction>", symbols.describe(pc))
+    }
+}
+
+fn print_event(dbg: &mut vm::Debugger, symbols: &SymbolTable, event: Result<vm::DebugEvent, vm::VMError>) {
+    for hit in dbg.vm_mut().take_watch_log() {
+        println!("{}", describe_hit(&hit, symbols));
+    }
+    match event {
+        Ok(vm::DebugEvent::Halted) => println!("Program halted"),
+        Ok(vm::DebugEvent::Breakpoint(adr)) => {
+            println!("Breakpoint at {}", symbols.describe(adr));
+            print_location(dbg, symbols);
+        },
+        Ok(vm::DebugEvent::Watchpoint(ref hit)) => {
+            println!("{}", describe_hit(hit, symbols));
+            print_location(dbg, symbols);
+        },
+        Ok(vm::DebugEvent::Stepped) | Ok(vm::DebugEvent::NeedsInput) => print_location(dbg, symbols),
+        Ok(vm::DebugEvent::HistoryStart) => {
+            println!("Reached the start of the history");
+            print_location(dbg, symbols);
+        },
+        Err(e) => println!("Error: {}", format_error(&e, symbols))
+    }
+}
+
+/// Runs `f` on the debugger, and whenever the program waits for input, reads a line of it from stdin and runs `f` again.
+fn with_input<F>(dbg: &mut vm::Debugger, mut f: F) -> Result<vm::DebugEvent, vm::VMError>
+        where F: FnMut(&mut vm::Debugger) -> Result<vm::DebugEvent, vm::VMError> {
+    loop {
+        match f(dbg) {
+            Ok(vm::DebugEvent::NeedsInput) => {},
+            event => return event
+        }
+
+        print!("(input) ");
+        stdout().flush().expect("Unable to write to stdout");
+        let mut line = String::new();
+        if stdin().read_line(&mut line).expect("Unable to read from stdin") == 0 {
+            // Out of input, so `in` reads 0 from now on
+            dbg.vm_mut().set_input_callback(|| 0);
+        } else if let Err(e) = dbg.vm_mut().provide_input(&line) {
+            println!("{}, try again", e);
+        }
+    }
+}
+
+fn debug_command(dbg: &mut vm::Debugger, symbols: &SymbolTable, line: &str) -> Result<bool, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let arg = |i: usize| args.get(i).cloned().ok_or("Missing argument".to_string());
+
+    match args[0] {
+        "s" | "step" => {
+            let n = match args.get(1) {
+                Some(n) => n.parse::<u32>().map_err(|_| "Invalid step count")?,
+                None => 1
+            };
+            let mut event = Ok(vm::DebugEvent::Stepped);
+            for _ in 0..n {
+                event = with_input(dbg, |d| d.step());
+                match event {
+                    Ok(vm::DebugEvent::Stepped) => {},
+                    _ => break
+                }
+            }
+            print_event(dbg, symbols, event);
+        },
+        "c" | "continue" => {
+            let event = with_input(dbg, |d| d.cont());
+            print_event(dbg, symbols, event);
+        },
+        "bs" | "back" => {
+            let n = match args.get(1) {
+                Some(n) => n.parse::<u32>().map_err(|_| "Invalid step count")?,
+                None => 1
+            };
+            let mut event = vm::DebugEvent::Stepped;
+            for _ in 0..n {
+                event = dbg.step_back();
+                if event != vm::DebugEvent::Stepped {
+                    break;
+                }
+            }
+            print_event(dbg, symbols, Ok(event));
+        },
+        "rc" | "reverse-continue" => {
+            let event = dbg.reverse_cont();
+            print_event(dbg, symbols, Ok(event));
+        },
+        "ww" | "whowrote" => {
+            let target = arg(1)?;
+            let index = if target.starts_with('$') {
+                let reg = target[1..].parse::<u8>().ok().filter(|r| *r < 8).ok_or(format!("Unknown register {}", target))?;
+                dbg.reverse_to_change(|c| match *c { vm::Change::Register(r, _) => r == reg, _ => false })
+            } else {
+                let adr = parse_location(target, symbols)?;
+                dbg.reverse_to_change(|c| match *c { vm::Change::Memory(a, _) => a == adr, _ => false })
+            };
+            match index {
+                Some(index) => {
+                    println!("Written by instruction {}", index);
+                    print_location(dbg, symbols);
+                },
+                None => return Err(format!("Nothing in the history wrote {}", target))
+            }
+        },
+        "b" | "break" => {
+            let adr = parse_location(arg(1)?, symbols)?;
+            dbg.add_breakpoint(adr);
+            println!("Breakpoint set at {}", symbols.describe(adr));
+        },
+        "d" | "delete" => {
+            let adr = parse_location(arg(1)?, symbols)?;
+            if !dbg.remove_breakpoint(adr) {
+                return Err(format!("No breakpoint at {}", symbols.describe(adr)));
+            }
+        },
+        "bl" | "breakpoints" => {
+            for adr in dbg.breakpoints() {
+                println!("{}", symbols.describe(adr));
+            }
+        },
+        "w" | "watch" => {
+            let watchpoint = parse_watchpoint(&args[1..], symbols)?;
+            let id = dbg.vm_mut().add_watchpoint(watchpoint);
+            println!("Watchpoint {}: {}", id, describe_watchpoint(&watchpoint, symbols));
+        },
+        "wd" | "unwatch" => {
+            let id = arg(1)?.parse::<usize>().map_err(|_| "Invalid watchpoint number")?;
+            if !dbg.vm_mut().remove_watchpoint(id) {
+                return Err(format!("No watchpoint {}", id));
+            }
+        },
+        "wl" | "watchpoints" => {
+            for &(id, ref w) in dbg.vm().watchpoints() {
+                println!("{}: {}", id, describe_watchpoint(w, symbols));
+            }
+        },
+        "r" | "regs" => {
+            let regs = dbg.vm().registers();
+            for (i, v) in regs.iter().enumerate() {
+                println!("${} = {:5} (x{:04x})", i, v, v);
+            }
+            print_location(dbg, symbols);
+        },
+        "st" | "stack" => {
+            let stack = dbg.vm().stack();
+            println!("{} entries", stack.len());
+            for (i, v) in stack.iter().enumerate() {
+                println!("{:4}: {:5} (x{:04x})", i, v, v);
+            }
+        },
+        "m" | "mem" => {
+            let start = parse_location(arg(1)?, symbols)? as usize;
+            let n = match args.get(2) {
+                Some(n) => n.parse::<usize>().map_err(|_| "Invalid word count")?,
+                None => 16
+            };
+            let mem = dbg.vm().memory();
+            let end = if start + n > mem.len() { mem.len() } else { start + n };
+            for (i, row) in mem[start..end].chunks(8).enumerate() {
+                let words: Vec<String> = row.iter().map(|w| format!("{:04x}", w)).collect();
+                println!("x{:04x}: {}", start + i * 8, words.join(" "));
+            }
+        },
+        "save" => {
+            save_snapshot(dbg.vm(), arg(1)?)?;
+        },
+        "h" | "help" => println!("{}", DEBUG_HELP),
+        "q" | "quit" => return Ok(false),
+        _ => return Err(format!("Unknown command {}, try help", args[0]))
+    }
+    Ok(true)
+}
+
+fn debug(vm: vm::VM, symbols: &SymbolTable) -> Result<(), String> {
+    let mut dbg = vm::Debugger::new(vm);
+    print_location(&dbg, symbols);
+
+    let mut last = String::new();
+    loop {
+        print!("(synvm) ");
+        stdout().flush().map_err(|_| "Unable to write to stdout")?;
+
+        let mut line = String::new();
+        if stdin().read_line(&mut line).map_err(|_| "Unable to read from stdin")? == 0 {
+            return Ok(());
+        }
+        if line.trim().is_empty() {
+            line = last.clone();
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match debug_command(&mut dbg, symbols, &line) {
+            Ok(true) => {},
+            Ok(false) => return Ok(()),
+            Err(e) => println!("{}", e)
+        }
+        last = line;
+    }
+}
+
+fn format_error(e: &vm::VMError, symbols: &SymbolTable) -> String {
+    match e.pc().and_then(|pc| symbols.symbolize(pc)) {
+        Some(s) => format!("{} (in {})", e, s),
+        None => e.to_string()
+    }
+}
+
+fn save_snapshot(vm: &vm::VM, file_name: &str) -> Result<(), String> {
+    let mut file = File::create(file_name).map_err(|_| "Unable to open snapshot file")?;
+    vm.snapshot().write(&mut file).map_err(|_| "Unable to write snapshot")?;
+    println!("Saved snapshot to {}", file_name);
+    Ok(())
+}
+
+/// Runs the program with input read from stdin a line at a time.
+/// Lines starting with `!` are commands for synvm rather than input for the program. They are only read
+/// while the program waits at an `in` instruction, so a snapshot saved with `!save` resumes at that same prompt.
+fn run_interactive(vm: &mut vm::VM, symbols: &SymbolTable) -> Result<(), String> {
+    loop {
+        match vm.run_until(None, |_| false) {
+            vm::StopReason::Halted => return Ok(()),
+            vm::StopReason::Error(e) => return Err(format_error(&e, symbols)),
+            vm::StopReason::NeedsInput => {},
+            vm::StopReason::Breakpoint(_) | vm::StopReason::Watchpoint(_) | vm::StopReason::StepLimit => unreachable!()
+        }
+
+        stdout().flush().map_err(|_| "Unable to write to stdout")?;
+        let mut line = String::new();
+        if stdin().read_line(&mut line).map_err(|_| "Unable to read from stdin")? == 0 {
+            // Out of input, so `in` reads 0 from now on
+            vm.set_input_callback(|| 0);
+            continue;
+        }
+
+        if line.starts_with('!') {
+            let args: Vec<&str> = line[1..].split_whitespace().collect();
+            match (args.get(0).cloned(), args.get(1)) {
+                (Some("save"), Some(file_name)) => save_snapshot(vm, file_name).unwrap_or_else(|e| println!("{}", e)),
+                _ => println!("Unknown command, available: !save <file>")
+            }
+            continue;
+        }
+        if let Err(e) = vm.provide_input(&line) {
+            println!("{}, try again", e);
+        }
+    }
+}
+
 
 fn run() -> Result<(), String> {
     let matches = App::new("synvm")
@@ -19,14 +414,69 @@ fn run() -> Result<(), String> {
             .long("asm")
             .value_name("asmfile")
             .takes_value(true))
+        .arg(Arg::with_name("include")
+            .short("I")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("DIR")
+            .requires("asm")
+            .help("Search this directory for .include and .incbin files when using --asm"))
         .arg(Arg::with_name("input_file")
             .short("f"))
         .arg(Arg::with_name("input_str")
             .short("i"))
-        .group(ArgGroup::with_name("code").args(&["binary", "asm"]).required(true))
+        .arg(Arg::with_name("resume")
+            .long("resume")
+            .value_name("snapshot")
+            .takes_value(true)
+            .help("Resume from a snapshot saved with !save or the debugger's save command"))
+        .arg(Arg::with_name("symbols")
+            .long("symbols")
+            .value_name("mapfile")
+            .takes_value(true)
+            .help("Load labels from a map file written by synasm --map"))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .value_name("file")
+            .takes_value(true)
+            .help("Log every executed instruction to a file"))
+        .arg(Arg::with_name("trace_range")
+            .long("trace-range")
+            .value_name("from-to")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("trace")
+            .help("Only trace instructions in this address range (addresses or labels)"))
+        .arg(Arg::with_name("trace_op")
+            .long("trace-op")
+            .value_name("mnemonic")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .requires("trace")
+            .help("Only trace instructions with this mnemonic"))
+        .arg(Arg::with_name("trace_window")
+            .long("trace-window")
+            .value_name("from-to")
+            .takes_value(true)
+            .requires("trace")
+            .help("Only trace the instructions executed with counts in from..to"))
+        .arg(Arg::with_name("debug")
+            .long("debug")
+            .help("Start an interactive debugger instead of running the program"))
+        .arg(Arg::with_name("history")
+            .long("history")
+            .value_name("n")
+            .takes_value(true)
+            .requires("debug")
+            .help("How many instructions the debugger can step back over (default 100000, 0 to disable)"))
+        .group(ArgGroup::with_name("code").args(&["binary", "asm", "resume"]).required(true))
         .group(ArgGroup::with_name("input").args(&["input_file", "input_str"]))
         .get_matches_safe().map_err(|x| { x.description().to_string() })?;
 
+    let mut symbols = SymbolTable::new();
     let mut vm = if let Some(file_name) = matches.value_of("binary") {
         let mut file = File::open(file_name).map_err(|_| "Unable to open input file")?;
         let vm = vm::VM::new_from_reader(&mut file);
@@ -36,47 +486,74 @@ fn run() -> Result<(), String> {
         let mut s = String::new();
         asm_file.read_to_string(&mut s).map_err(|_| "Unable to read asm input")?;
 
+        let mut asm = assembler::Assembler::new();
+        for dir in matches.values_of("include").into_iter().flat_map(|v| v) {
+            asm.add_include_path(dir);
+        }
+        let assembly = asm.assemble(asm_file_name, &s).map_err(|e| format!("While assembling code:\n{}", e))?;
+        symbols = SymbolTable::from_labels(assembly.labels());
+
         let mut out = Vec::new();
-        assembler::assemble(&mut out, &s).map_err(|e| format!("While assembling code: {}", match e {
-            assembler::AssemblerError::LabelResolveError(ref s) => format!("Unknown label :{}", s),
-            assembler::AssemblerError::ParserError(e) => format!("\n{}", e)
-        }))?;
+        assembly.write(&mut out);
 
         let mut slc: &[u8] = &mut out;
         vm::VM::new_from_reader(&mut slc)
+    } else if let Some(snapshot_name) = matches.value_of("resume") {
+        let mut file = File::open(snapshot_name).map_err(|_| "Unable to open snapshot file")?;
+        let snapshot = vm::Snapshot::read(&mut file).map_err(|e| format!("Unable to read snapshot: {}", e))?;
+        let mut vm = vm::VM::new();
+        vm.restore(&snapshot);
+        vm
     } else { unreachable!() };
 
+    if let Some(map_name) = matches.value_of("symbols") {
+        let mut file = File::open(map_name).map_err(|_| "Unable to open symbol file")?;
+        symbols = SymbolTable::read(&mut file).map_err(|e| format!("Unable to read symbol file: {}", e))?;
+    }
+    vm.set_symbols(symbols.clone());
+
+    let mut interactive = false;
+
     if let Some(f) = matches.value_of("input_file") {
         let mut input_file = File::open(f).map_err(|_| "Unable to open input file")?;
 
         let mut s = String::new();
         input_file.read_to_string(&mut s).map_err(|_| "Unable to read input file")?;
-
-        let mut char_iter = s.chars().collect::<Vec<_>>().into_iter();
-        vm.set_input_callback(move || {
-            char_iter.next().map_or(0, |x| x as u16)
-        });
+        vm.provide_input(&s).map_err(|e| e.to_string())?;
     } else if let Some(s) = matches.value_of("input_str") {
-        let mut char_iter = s.chars().collect::<Vec<_>>().into_iter();
-        vm.set_input_callback(move || {
-            char_iter.next().map_or(0, |x| x as u16)
-        });
+        vm.provide_input(s).map_err(|e| e.to_string())?;
     } else {
-        vm.set_input_callback(|| {
-            let x = stdin().bytes().next();
-            let y = x.unwrap_or(Ok(0));
-            let z = y.expect("Unable to read from stdin");
-            z as u16
-        });
+        interactive = true;
     }
     vm.set_output_callback(|v| {
         print!("{}", char::from_u32(v as u32).expect("Cannot convert to char"));
     });
-    vm.execute().map_err(|e| match e {
-        vm::VMError::PopFromEmptyStack => "Popped from empty stack".to_string(),
-        vm::VMError::UnknownInstruction(i) => format!("Unknown instruction {}", i),
-        vm::VMError::OOBRegister(i) => format!("Unknown register access {}", i)
-    })?;
+    if let Some(trace_name) = matches.value_of("trace") {
+        let filter = trace_filter(&matches, &symbols)?;
+        let trace_symbols = symbols.clone();
+        let mut out = BufWriter::new(File::create(trace_name).map_err(|_| "Unable to open trace file")?);
+        vm.set_trace_callback(move |entry| {
+            if filter.matches(entry) {
+                trace::write_entry(&mut out, entry, &trace_symbols).expect("Unable to write to trace file");
+            }
+        });
+    }
+    if matches.is_present("debug") {
+        if !interactive {
+            // Like without --debug, `in` reads 0 once the given input runs out
+            vm.set_input_callback(|| 0);
+        }
+        let history = match matches.value_of("history") {
+            Some(n) => n.parse::<usize>().map_err(|_| format!("Invalid history length {}", n))?,
+            None => 100000
+        };
+        vm.set_history_limit(history);
+        return debug(vm, &symbols);
+    }
+    if interactive {
+        return run_interactive(&mut vm, &symbols);
+    }
+    vm.execute().map_err(|e| format_error(&e, &symbols))?;
     Ok(())
 }
 
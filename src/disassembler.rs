@@ -0,0 +1,159 @@
+use ::assembler::ProgramElement;
+use ::instruction::{Instruction, Parameter};
+
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+pub fn read_words(reader: &mut Read) -> Vec<u16> {
+    let mut words = Vec::new();
+    while let Ok(v) = reader.read_u16::<LittleEndian>() {
+        words.push(v);
+    }
+    return words;
+}
+
+/// Decodes the instruction at `addr`. Anything that isn't a valid instruction (unknown opcodes,
+/// literals in register positions, invalid operand words) has no `synasm` spelling and should be treated as data.
+pub fn decode(words: &[u16], addr: u16) -> Option<Instruction> {
+    match words.get(addr as usize..) {
+        Some(slc) => Instruction::decode(slc).ok(),
+        None => None
+    }
+}
+
+fn label_name(addr: u16) -> String {
+    format!("L_{:04x}", addr)
+}
+
+fn branch_target_mut(instr: &mut Instruction) -> Option<&mut Parameter> {
+    match *instr {
+        Instruction::Jmp(ref mut a) => Some(a),
+        Instruction::Jt(_, ref mut b) => Some(b),
+        Instruction::Jf(_, ref mut b) => Some(b),
+        Instruction::Call(ref mut a) => Some(a),
+        _ => None
+    }
+}
+
+/// Linearly sweeps `words` from address 0, returning every instruction and data run with its address.
+/// Literal branch targets that land on a decoded instruction are replaced with generated labels.
+pub fn disassemble(words: &[u16]) -> Vec<(u16, ProgramElement)> {
+    let mut decoded: Vec<(u16, ProgramElement)> = Vec::new();
+
+    let mut addr = 0usize;
+    while addr < words.len() {
+        if let Some(instr) = decode(words, addr as u16) {
+            let len = instr.len() as usize;
+            decoded.push((addr as u16, ProgramElement::Instruction(instr)));
+            addr += len;
+            continue;
+        }
+
+        let append = match decoded.last() {
+            Some(&(_, ProgramElement::Data(ref d))) => d.len() < 8,
+            _ => false
+        };
+        if append {
+            if let Some(&mut (_, ProgramElement::Data(ref mut d))) = decoded.last_mut() {
+                d.push(words[addr]);
+            }
+        } else {
+            decoded.push((addr as u16, ProgramElement::Data(vec![words[addr]])));
+        }
+        addr += 1;
+    }
+
+    let starts: HashSet<u16> = decoded.iter()
+        .filter(|&&(_, ref e)| if let ProgramElement::Instruction(_) = *e { true } else { false })
+        .map(|&(a, _)| a)
+        .collect();
+
+    let mut targets = HashSet::new();
+    for &mut (_, ref mut elem) in decoded.iter_mut() {
+        if let ProgramElement::Instruction(ref mut instr) = *elem {
+            if let Some(target) = branch_target_mut(instr) {
+                let adr = match *target {
+                    Parameter::Literal(adr) if starts.contains(&adr) => adr,
+                    _ => continue
+                };
+                *target = Parameter::Label(label_name(adr));
+                targets.insert(adr);
+            }
+        }
+    }
+
+    let mut res = Vec::with_capacity(decoded.len() + targets.len());
+    for (adr, elem) in decoded {
+        if targets.contains(&adr) {
+            res.push((adr, ProgramElement::Label(label_name(adr))));
+        }
+        res.push((adr, elem));
+    }
+    return res;
+}
+
+fn format_instruction(instr: &Instruction) -> String {
+    match *instr {
+        Instruction::Out(Parameter::Literal(c)) if c > 0x20 && c < 0x7f => format!("out '{}'", c as u8 as char),
+        _ => instr.to_string()
+    }
+}
+
+/// Writes disassembled elements back out as `synasm` source, optionally annotating each line with its address.
+pub fn write_source(out: &mut Write, elems: &[(u16, ProgramElement)], addresses: bool) -> io::Result<()> {
+    for &(adr, ref elem) in elems {
+        let line = match *elem {
+            ProgramElement::Label(ref s) => {
+                writeln!(out, "{}:", s)?;
+                continue;
+            },
+            ProgramElement::Instruction(ref instr) => format!("    {}", format_instruction(instr)),
+            ProgramElement::Data(ref d) => {
+                let words: Vec<String> = d.iter().map(|w| format!("x{:04x}", w)).collect();
+                format!("    .word {}", words.join(", "))
+            },
+            ProgramElement::Constant(ref name, ref value) => format!(".equ {} {}", name, value),
+            ProgramElement::Org(ref adr) => format!(".org {}", adr),
+            ProgramElement::Align(ref n) => format!(".align {}", n)
+        };
+
+        if addresses {
+            writeln!(out, "{:<40}; x{:04x}", line, adr)?;
+        } else {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::tests::image;
+
+    fn reassemble(words: &[u16], addresses: bool) -> Vec<u16> {
+        let mut out = Vec::new();
+        write_source(&mut out, &disassemble(words), addresses).unwrap();
+        image(&String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn round_trips_through_the_assembler() {
+        let words = image("start: in $0\n    jt $0 :data\n    call :f\n    jmp :start\n\
+                           f:  out 'x'\n    out 10\n    wmem :data $0\n    ret\n\
+                           data: .word 40000, 22, 1, 5, 32776, 7\n    .word 65535, 9, 10, 11, 12, 13, 14, 15, 16\n    halt\n");
+        assert_eq!(reassemble(&words, false), words);
+        assert_eq!(reassemble(&words, true), words);
+    }
+
+    #[test]
+    fn labels_branch_targets_and_keeps_data() {
+        let words = image("start: call :f\n    jmp :start\nf:  ret\n    .word 40000, 1, 5\n    halt\n");
+        let mut out = Vec::new();
+        write_source(&mut out, &disassemble(&words), false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "L_0000:\n    call :L_0004\n    jmp :L_0000\nL_0004:\n    ret\n    .word x9c40, x0001, x0005\n    halt\n");
+    }
+}
@@ -0,0 +1,152 @@
+use ::assembler::{AssemblerError, ProgramElement, SourceElement};
+use ::parser::{self, Statement};
+use ::source::{SourceMap, Span};
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Looks for `name` next to the file that includes it, then in each of the search paths.
+fn find(name: &str, including: &Path, search_paths: &[PathBuf]) -> Option<PathBuf> {
+    let dir = including.parent().unwrap_or(Path::new(""));
+    let mut candidates = vec![dir.join(name)];
+    candidates.extend(search_paths.iter().map(|p| p.join(name)));
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut buf = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| format!("unable to read `{}`: {}", path.display(), e))?;
+    Ok(buf)
+}
+
+struct Includer<'a> {
+    search_paths: &'a [PathBuf],
+    map: &'a mut SourceMap,
+    errors: &'a mut Vec<(Span, String)>,
+    /// The files currently being included, outermost first, as found and canonicalized for detecting cycles.
+    stack: Vec<(PathBuf, PathBuf)>,
+    /// Every file included so far, canonicalized. Later includes of them are skipped.
+    included: HashSet<PathBuf>
+}
+
+impl<'a> Includer<'a> {
+    fn resolve(&mut self, statements: Vec<Statement>, out: &mut Vec<Statement>) -> Result<(), AssemblerError> {
+        for statement in statements {
+            match statement {
+                Statement::Include { span, ref path } => self.include(span, path, out)?,
+                Statement::Incbin { span, ref path } => self.incbin(span, path, out),
+                s => out.push(s)
+            }
+        }
+        Ok(())
+    }
+
+    fn locate(&mut self, span: Span, name: &str) -> Option<PathBuf> {
+        let found = find(name, &self.stack.last().unwrap().0, self.search_paths);
+        if found.is_none() {
+            self.errors.push((span, format!("can't find `{}` in the include path", name)));
+        }
+        found
+    }
+
+    fn include(&mut self, span: Span, name: &str, out: &mut Vec<Statement>) -> Result<(), AssemblerError> {
+        let path = match self.locate(span, name) {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        let canonical = fs::canonicalize(&path).unwrap_or(path.clone());
+        if self.stack.iter().any(|&(_, ref c)| *c == canonical) {
+            self.errors.push((span, format!("`{}` includes itself", name)));
+            return Ok(());
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let src = match read_file(&path).and_then(|b| String::from_utf8(b).map_err(|_| format!("`{}` is not valid UTF-8", name))) {
+            Ok(src) => src,
+            Err(e) => {
+                self.errors.push((span, e));
+                return Ok(());
+            }
+        };
+        let display_name = path.display().to_string();
+        let file = self.map.add_include(&display_name, src.clone(), span);
+        let (statements, errors) = parser::parse(file, &src, &parser::Aliases::new())
+            .map_err(|e| AssemblerError::ParserError(format!("{}:\n{}", display_name, e)))?;
+        self.errors.extend(errors);
+
+        self.stack.push((path, canonical));
+        self.resolve(statements, out)?;
+        self.stack.pop();
+        Ok(())
+    }
+
+    fn incbin(&mut self, span: Span, name: &str, out: &mut Vec<Statement>) {
+        let path = match self.locate(span, name) {
+            Some(p) => p,
+            None => return
+        };
+        match read_file(&path) {
+            Ok(ref bytes) if bytes.len() % 2 != 0 =>
+                self.errors.push((span, format!("`{}` has an odd number of bytes, so it can't be read as words", name))),
+            Ok(bytes) => out.push(Statement::Element(SourceElement {
+                span: span,
+                operands: Vec::new(),
+                elem: ProgramElement::Data(bytes.chunks(2).map(LittleEndian::read_u16).collect()),
+                generated: false
+            })),
+            Err(e) => self.errors.push((span, e))
+        }
+    }
+}
+
+/// Replaces every `.include` with the statements of the included file and every `.incbin` with its words.
+/// Files are looked up relative to the file including them first, then in `search_paths`. Each file is only
+/// included the first time, so that a library several files need can include them all.
+pub fn resolve(statements: Vec<Statement>, file_name: &str, search_paths: &[PathBuf], map: &mut SourceMap,
+               errors: &mut Vec<(Span, String)>) -> Result<Vec<Statement>, AssemblerError> {
+    let main = PathBuf::from(file_name);
+    let mut includer = Includer {
+        search_paths: search_paths,
+        map: map,
+        errors: errors,
+        stack: vec![(main.clone(), fs::canonicalize(&main).unwrap_or(main))],
+        included: HashSet::new()
+    };
+    let mut out = Vec::new();
+    includer.resolve(statements, &mut out)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use ::assembler::Assembler;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    #[test]
+    fn includes_a_file_once() {
+        let dir = env::temp_dir().join(format!("rustacor-includes-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = [
+            ("lib.synasm", "lib: ret\n"),
+            ("a.synasm", ".include \"lib.synasm\"\na: call :lib\n"),
+            ("b.synasm", ".include \"lib.synasm\"\nb: call :lib\n")
+        ];
+        for &(name, src) in &files {
+            File::create(dir.join(name)).unwrap().write_all(src.as_bytes()).unwrap();
+        }
+        let main = dir.join("main.synasm");
+        let assembly = Assembler::new().assemble(main.to_str().unwrap(), ".include \"a.synasm\"\n.include \"b.synasm\"\nhalt\n");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(assembly.unwrap().image(), vec![18, 17, 0, 17, 0, 0]);
+    }
+}
+
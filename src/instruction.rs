@@ -1,3 +1,4 @@
+use std::fmt;
 use std::io::Write;
 use ::byteorder::{LittleEndian, WriteBytesExt};
 
@@ -8,11 +9,14 @@ impl Register {
     fn to_word(&self) -> u16 {
         return 32768 + self.0 as u16;
     }
-}
 
-impl From<u16> for Register {
-    fn from(p: u16) -> Self {
-        return Register(p as u8);
+    /// Decodes a register operand word, which must be in 32768..32775.
+    pub fn from_word(w: u16) -> Option<Register> {
+        if w >= 32768 && w <= 32775 {
+            Some(Register((w - 32768) as u8))
+        } else {
+            None
+        }
     }
 }
 
@@ -20,7 +24,29 @@ impl From<u16> for Register {
 pub enum Parameter {
     Literal(u16),
     Register(Register),
-    Label(String)
+    Label(String),
+    /// A name defined with `.equ`, resolved by the assembler.
+    Constant(String),
+    /// Arithmetic on labels, constants and literals, evaluated by the assembler.
+    Expr(Box<Expr>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Shl,
+    Shr,
+    And,
+    Or
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    /// A literal, label or constant.
+    Term(Parameter),
+    Binary(BinOp, Box<Expr>, Box<Expr>)
 }
 
 impl Parameter {
@@ -28,22 +54,34 @@ impl Parameter {
         match *self {
             Parameter::Literal(x) => x,
             Parameter::Register(ref x) => x.to_word(),
-            Parameter::Label(_) => panic!("Cannot byteify a label reference - reify first")
+            Parameter::Label(_) => panic!("Cannot byteify a label reference - reify first"),
+            Parameter::Constant(_) => panic!("Cannot byteify a constant reference - reify first"),
+            Parameter::Expr(_) => panic!("Cannot byteify an expression - reify first")
         }
     }
 }
 
-impl From<u16> for Parameter {
-    fn from(p: u16) -> Self {
-        if p <= 32767 {
-            return Parameter::Literal(p);
-        } else {
-            return Parameter::Register(p.into());
+impl Parameter {
+    /// Decodes an operand word: 0..32767 is a literal, 32768..32775 a register, and anything above is invalid.
+    pub fn from_word(w: u16) -> Option<Parameter> {
+        if w <= 32767 {
+            return Some(Parameter::Literal(w));
         }
+        Register::from_word(w).map(Parameter::Register)
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode(u16),
+    /// The instruction continues past the end of the input.
+    Truncated,
+    /// The operand word at `index` (1-based, counting the opcode as 0) is invalid in its position:
+    /// either above 32775, or a literal where the instruction writes to a register.
+    InvalidOperand { index: usize, word: u16 }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Halt,
     Set(Register, Parameter),
@@ -101,10 +139,164 @@ impl Instruction {
         }
     }
 
+    pub fn is_valid_idx(idx: u16) -> bool {
+        idx <= 21 || idx == 0xff
+    }
+
     pub fn len(&self) -> u16 {
         return Instruction::len_by_idx(self.idx());
     }
-    fn idx(&self) -> u16 {
+
+    /// Decodes the instruction at the start of `words`, validating that every operand is legal in its position.
+    pub fn decode(words: &[u16]) -> Result<Instruction, DecodeError> {
+        let idx = match words.first() {
+            Some(&idx) if Instruction::is_valid_idx(idx) => idx,
+            Some(&idx) => return Err(DecodeError::UnknownOpcode(idx)),
+            None => return Err(DecodeError::Truncated)
+        };
+        if words.len() < Instruction::len_by_idx(idx) as usize {
+            return Err(DecodeError::Truncated);
+        }
+
+        let invalid = |i: usize| DecodeError::InvalidOperand { index: i, word: words[i] };
+        let r = |i: usize| Register::from_word(words[i]).ok_or(invalid(i));
+        let p = |i: usize| Parameter::from_word(words[i]).ok_or(invalid(i));
+        Ok(match idx {
+            0 => Instruction::Halt,
+            1 => Instruction::Set(r(1)?, p(2)?),
+            2 => Instruction::Push(p(1)?),
+            3 => Instruction::Pop(r(1)?),
+            4 => Instruction::Eq(r(1)?, p(2)?, p(3)?),
+            5 => Instruction::Gt(r(1)?, p(2)?, p(3)?),
+            6 => Instruction::Jmp(p(1)?),
+            7 => Instruction::Jt(p(1)?, p(2)?),
+            8 => Instruction::Jf(p(1)?, p(2)?),
+            9 => Instruction::Add(r(1)?, p(2)?, p(3)?),
+            10 => Instruction::Mult(r(1)?, p(2)?, p(3)?),
+            11 => Instruction::Mod(r(1)?, p(2)?, p(3)?),
+            12 => Instruction::And(r(1)?, p(2)?, p(3)?),
+            13 => Instruction::Or(r(1)?, p(2)?, p(3)?),
+            14 => Instruction::Not(r(1)?, p(2)?),
+            15 => Instruction::Rmem(r(1)?, p(2)?),
+            16 => Instruction::Wmem(p(1)?, p(2)?),
+            17 => Instruction::Call(p(1)?),
+            18 => Instruction::Ret,
+            19 => Instruction::Out(p(1)?),
+            20 => Instruction::In(r(1)?),
+            21 => Instruction::Noop,
+            _ => Instruction::Dmp
+        })
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Instruction::Halt => "halt",
+            Instruction::Set(_, _) => "set",
+            Instruction::Push(_) => "push",
+            Instruction::Pop(_) => "pop",
+            Instruction::Eq(_, _, _) => "eq",
+            Instruction::Gt(_, _, _) => "gt",
+            Instruction::Jmp(_) => "jmp",
+            Instruction::Jt(_, _) => "jt",
+            Instruction::Jf(_, _) => "jf",
+            Instruction::Add(_, _, _) => "add",
+            Instruction::Mult(_, _, _) => "mult",
+            Instruction::Mod(_, _, _) => "mod",
+            Instruction::And(_, _, _) => "and",
+            Instruction::Or(_, _, _) => "or",
+            Instruction::Not(_, _) => "not",
+            Instruction::Rmem(_, _) => "rmem",
+            Instruction::Wmem(_, _) => "wmem",
+            Instruction::Call(_) => "call",
+            Instruction::Ret => "ret",
+            Instruction::Out(_) => "out",
+            Instruction::In(_) => "in",
+            Instruction::Noop => "noop",
+            Instruction::Dmp => "dmp"
+        }
+    }
+
+    /// Whether any operand is still an unresolved label or constant reference.
+    pub fn has_label(&self) -> bool {
+        let is_label = |p: &Parameter| match *p {
+            Parameter::Label(_) | Parameter::Constant(_) | Parameter::Expr(_) => true,
+            _ => false
+        };
+        match *self {
+            Instruction::Set(_, ref b) |
+            Instruction::Not(_, ref b) |
+            Instruction::Rmem(_, ref b) => is_label(b),
+            Instruction::Push(ref a) |
+            Instruction::Jmp(ref a) |
+            Instruction::Call(ref a) |
+            Instruction::Out(ref a) => is_label(a),
+            Instruction::Jt(ref a, ref b) |
+            Instruction::Jf(ref a, ref b) |
+            Instruction::Wmem(ref a, ref b) => is_label(a) || is_label(b),
+            Instruction::Eq(_, ref b, ref c) |
+            Instruction::Gt(_, ref b, ref c) |
+            Instruction::Add(_, ref b, ref c) |
+            Instruction::Mult(_, ref b, ref c) |
+            Instruction::Mod(_, ref b, ref c) |
+            Instruction::And(_, ref b, ref c) |
+            Instruction::Or(_, ref b, ref c) => is_label(b) || is_label(c),
+            _ => false
+        }
+    }
+
+    /// All operands in encoding order, with register targets wrapped as `Parameter::Register`.
+    pub fn operands(&self) -> Vec<Parameter> {
+        match *self {
+            Instruction::Set(ref a, ref b) |
+            Instruction::Not(ref a, ref b) |
+            Instruction::Rmem(ref a, ref b) => vec![Parameter::Register(a.clone()), b.clone()],
+            Instruction::Pop(ref a) |
+            Instruction::In(ref a) => vec![Parameter::Register(a.clone())],
+            Instruction::Push(ref a) |
+            Instruction::Jmp(ref a) |
+            Instruction::Call(ref a) |
+            Instruction::Out(ref a) => vec![a.clone()],
+            Instruction::Jt(ref a, ref b) |
+            Instruction::Jf(ref a, ref b) |
+            Instruction::Wmem(ref a, ref b) => vec![a.clone(), b.clone()],
+            Instruction::Eq(ref a, ref b, ref c) |
+            Instruction::Gt(ref a, ref b, ref c) |
+            Instruction::Add(ref a, ref b, ref c) |
+            Instruction::Mult(ref a, ref b, ref c) |
+            Instruction::Mod(ref a, ref b, ref c) |
+            Instruction::And(ref a, ref b, ref c) |
+            Instruction::Or(ref a, ref b, ref c) => vec![Parameter::Register(a.clone()), b.clone(), c.clone()],
+            Instruction::Halt | Instruction::Ret | Instruction::Noop | Instruction::Dmp => vec![]
+        }
+    }
+
+    /// The operands that aren't register targets, for rewriting them in place.
+    pub fn params_mut(&mut self) -> Vec<&mut Parameter> {
+        match *self {
+            Instruction::Set(_, ref mut b) |
+            Instruction::Not(_, ref mut b) |
+            Instruction::Rmem(_, ref mut b) |
+            Instruction::Push(ref mut b) |
+            Instruction::Jmp(ref mut b) |
+            Instruction::Call(ref mut b) |
+            Instruction::Out(ref mut b) => vec![b],
+            Instruction::Jt(ref mut a, ref mut b) |
+            Instruction::Jf(ref mut a, ref mut b) |
+            Instruction::Wmem(ref mut a, ref mut b) |
+            Instruction::Eq(_, ref mut a, ref mut b) |
+            Instruction::Gt(_, ref mut a, ref mut b) |
+            Instruction::Add(_, ref mut a, ref mut b) |
+            Instruction::Mult(_, ref mut a, ref mut b) |
+            Instruction::Mod(_, ref mut a, ref mut b) |
+            Instruction::And(_, ref mut a, ref mut b) |
+            Instruction::Or(_, ref mut a, ref mut b) => vec![a, b],
+            Instruction::Pop(_) | Instruction::In(_) |
+            Instruction::Halt | Instruction::Ret | Instruction::Noop | Instruction::Dmp => vec![]
+        }
+    }
+
+    /// The opcode, which is the first word of the encoded instruction.
+    pub fn idx(&self) -> u16 {
         match *self {
             Instruction::Halt => 0,
             Instruction::Set(_, _) => 1,
@@ -161,4 +353,99 @@ impl Instruction {
             out.write_u16::<LittleEndian>(*b).expect("Unable to write to output");
         }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+impl fmt::Display for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Parameter::Literal(x) => write!(f, "{}", x),
+            Parameter::Register(ref r) => write!(f, "{}", r),
+            Parameter::Label(ref s) => write!(f, ":{}", s),
+            Parameter::Constant(ref s) => write!(f, "{}", s),
+            Parameter::Expr(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Shl => "<<",
+            BinOp::Shr => ">>",
+            BinOp::And => "&",
+            BinOp::Or => "|"
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Nested operations are parenthesized, so the output parses back into the same tree.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Term(ref p) => write!(f, "{}", p),
+            Expr::Binary(op, ref a, ref b) => {
+                let side = |e: &Expr| match *e {
+                    Expr::Term(_) => e.to_string(),
+                    _ => format!("({})", e)
+                };
+                write!(f, "{} {} {}", side(a), op, side(b))
+            }
+        }
+    }
+}
+
+/// Formats the instruction in `synasm` syntax.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        for op in self.operands() {
+            write!(f, " {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reg(r: u8) -> Parameter {
+        Parameter::Register(Register(r))
+    }
+
+    #[test]
+    fn decodes_registers_and_literals() {
+        for w in 32768..32776 {
+            assert_eq!(Instruction::decode(&[2, w]), Ok(Instruction::Push(reg((w - 32768) as u8))));
+        }
+        assert_eq!(Instruction::decode(&[9, 32775, 32767, 32768, 99]),
+                   Ok(Instruction::Add(Register(7), Parameter::Literal(32767), reg(0))));
+    }
+
+    #[test]
+    fn rejects_invalid_operands() {
+        assert_eq!(Instruction::decode(&[2, 32776]), Err(DecodeError::InvalidOperand { index: 1, word: 32776 }));
+        assert_eq!(Instruction::decode(&[16, 5, 65535]), Err(DecodeError::InvalidOperand { index: 2, word: 65535 }));
+        // The first operand of these is the register they write to
+        assert_eq!(Instruction::decode(&[1, 5, 6]), Err(DecodeError::InvalidOperand { index: 1, word: 5 }));
+        assert_eq!(Instruction::decode(&[20, 0]), Err(DecodeError::InvalidOperand { index: 1, word: 0 }));
+        assert_eq!(Instruction::decode(&[15, 32776, 1]), Err(DecodeError::InvalidOperand { index: 1, word: 32776 }));
+    }
+
+    #[test]
+    fn rejects_unknown_and_truncated_instructions() {
+        assert_eq!(Instruction::decode(&[22]), Err(DecodeError::UnknownOpcode(22)));
+        assert_eq!(Instruction::decode(&[4, 32768, 1]), Err(DecodeError::Truncated));
+        assert_eq!(Instruction::decode(&[]), Err(DecodeError::Truncated));
+    }
+}
@@ -4,9 +4,18 @@ extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
+pub mod analysis;
 pub mod assembler;
+pub mod disassembler;
+pub mod includes;
 pub mod instruction;
+pub mod macros;
 pub mod parser;
+pub mod pseudo;
+pub mod source;
+pub mod symbols;
+pub mod trace;
+pub mod translate;
 pub mod vm;
 
 #[cfg(test)]
@@ -0,0 +1,320 @@
+use ::parser::{self, Aliases, Statement};
+use ::source::{Expansion, SourceMap, Span};
+
+use std::collections::HashMap;
+
+/// How deep macros may call each other before we assume one of them is recursive.
+const MAX_DEPTH: usize = 64;
+
+/// A `.macro name arg, ... .endm` definition. Parameters are referred to as `\arg` in the body.
+#[derive(Debug, Clone)]
+pub struct MacroDef {
+    pub name: String,
+    pub params: Vec<String>,
+    /// The text between the `.macro` line and `.endm`, starting with the newline that ends the `.macro` line.
+    pub body: String,
+    pub body_span: Span,
+    pub span: Span
+}
+
+#[derive(Debug, Clone)]
+pub struct MacroCall {
+    pub span: Span,
+    pub name: String,
+    pub args: Vec<String>,
+    /// The register aliases live at the call, which the expansion can use too.
+    pub aliases: Aliases
+}
+
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Text,
+    Ident,
+    Param
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits a macro body into identifiers, `\param` references and everything else.
+/// Comments, strings and char literals are kept whole so nothing inside them is substituted.
+fn scan(body: &str) -> Vec<(Piece, usize, usize)> {
+    let mut pieces = Vec::new();
+    let mut chars = body.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let mut end = start + c.len_utf8();
+        let piece = match c {
+            ';' | '"' => {
+                let mut escaped = false;
+                while let Some(&(i, d)) = chars.peek() {
+                    if d == '\n' {
+                        break;
+                    }
+                    chars.next();
+                    end = i + d.len_utf8();
+                    if c == '"' && d == '"' && !escaped {
+                        break;
+                    }
+                    escaped = d == '\\' && !escaped;
+                }
+                Piece::Text
+            },
+            '\'' => {
+                for _ in 0..2 {
+                    if let Some((i, d)) = chars.next() {
+                        end = i + d.len_utf8();
+                    }
+                }
+                Piece::Text
+            },
+            c if c == '\\' || is_ident_char(c) => {
+                while let Some(&(i, d)) = chars.peek() {
+                    if !is_ident_char(d) {
+                        break;
+                    }
+                    chars.next();
+                    end = i + d.len_utf8();
+                }
+                if c != '\\' {
+                    Piece::Ident
+                } else if end > start + 1 {
+                    Piece::Param
+                } else {
+                    Piece::Text
+                }
+            },
+            _ => Piece::Text
+        };
+        pieces.push((piece, start, end));
+    }
+    return pieces;
+}
+
+/// Splits the arguments of a macro call at the commas that aren't inside a string or char literal.
+pub fn split_args(args: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut from = 0;
+    for (piece, start, end) in scan(args) {
+        if piece == Piece::Text && &args[start..end] == "," {
+            out.push(args[from..start].trim().to_string());
+            from = end;
+        }
+    }
+    out.push(args[from..].trim().to_string());
+    return out;
+}
+
+/// Whether `name` refers to a numeric label, like `1f` or `2b`.
+fn is_numeric_ref(name: &str) -> bool {
+    name.len() > 1 && (name.ends_with('f') || name.ends_with('b')) && name[..(name.len() - 1)].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Produces the text of one expansion of `def`. Labels defined in the body get the suffix `@n`, so every
+/// expansion has its own copy of them. Numeric labels are renamed too, and `:1f`/`:1b` in the body are pointed at
+/// the ones in the body if there are any in that direction.
+fn substitute(def: &MacroDef, args: &[String], n: usize) -> Result<String, (Span, String)> {
+    let body = &def.body;
+    let pieces = scan(body);
+
+    // Whether the identifier at `start..end` is a label definition, and the label's name including any leading `.`
+    let label_at = |start: usize, end: usize| -> Option<(bool, &str)> {
+        let name_start = if body[..start].ends_with('.') { start - 1 } else { start };
+        let before = &body[..name_start];
+        if before.ends_with(':') {
+            Some((false, &body[name_start..end]))
+        } else if body[end..].starts_with(':') && !before.ends_with(is_ident_char) {
+            Some((true, &body[name_start..end]))
+        } else {
+            None
+        }
+    };
+    let mut locals: Vec<&str> = Vec::new();
+    let mut numeric: Vec<(usize, &str)> = Vec::new();
+    for &(ref piece, start, end) in &pieces {
+        if let (&Piece::Ident, Some((true, name))) = (piece, label_at(start, end)) {
+            if name.chars().all(|c| c.is_ascii_digit()) {
+                numeric.push((start, name));
+            } else {
+                locals.push(name);
+            }
+        }
+    }
+    let numeric_name = |k: usize| format!("{}_{}@{}", numeric[k].1, k, n);
+
+    let mut out = String::new();
+    for (piece, start, end) in pieces {
+        let text = &body[start..end];
+        match piece {
+            Piece::Ident => match label_at(start, end) {
+                Some((true, _)) if text.chars().all(|c| c.is_ascii_digit()) =>
+                    out.push_str(&numeric_name(numeric.iter().position(|&(s, _)| s == start).unwrap())),
+                Some((_, name)) if locals.contains(&name) => out.push_str(&format!("{}@{}", text, n)),
+                Some((false, _)) if is_numeric_ref(text) => {
+                    let (num, dir) = text.split_at(text.len() - 1);
+                    let target = if dir == "f" {
+                        numeric.iter().position(|&(s, name)| s > start && name == num)
+                    } else {
+                        numeric.iter().rposition(|&(s, name)| s < start && name == num)
+                    };
+                    match target {
+                        Some(k) => out.push_str(&numeric_name(k)),
+                        None => out.push_str(text)
+                    }
+                },
+                _ => out.push_str(text)
+            },
+            Piece::Param => match def.params.iter().position(|p| p == &text[1..]) {
+                Some(i) => out.push_str(&args[i]),
+                None => {
+                    let span = Span { file: def.body_span.file, start: def.body_span.start + start, end: def.body_span.start + end };
+                    return Err((span, format!("macro `{}` has no parameter `{}`", def.name, &text[1..])));
+                }
+            },
+            _ => out.push_str(text)
+        }
+    }
+    return Ok(out);
+}
+
+struct Expander<'a> {
+    macros: HashMap<String, MacroDef>,
+    map: &'a mut SourceMap,
+    errors: &'a mut Vec<(Span, String)>,
+    expansions: usize,
+    out: Vec<Statement>
+}
+
+impl<'a> Expander<'a> {
+    fn expand_statements(&mut self, statements: Vec<Statement>, depth: usize) {
+        for statement in statements {
+            match statement {
+                s @ Statement::Element(_) | s @ Statement::Pseudo { .. } | s @ Statement::Scratch { .. } |
+                s @ Statement::Alias { .. } => self.out.push(s),
+                Statement::Macro(ref def) if depth > 0 =>
+                    self.errors.push((def.span, "macros can't be defined inside other macros".to_string())),
+                Statement::Macro(_) => {},
+                Statement::MacroCall(call) => self.expand_call(call, depth),
+                Statement::Include { span, .. } | Statement::Incbin { span, .. } =>
+                    self.errors.push((span, "files can't be included inside a macro".to_string()))
+            }
+        }
+    }
+
+    fn expand_call(&mut self, call: MacroCall, depth: usize) {
+        let def = match self.macros.get(&call.name) {
+            Some(def) => def.clone(),
+            None => {
+                self.errors.push((call.span, format!("unknown instruction or macro `{}`", call.name)));
+                return;
+            }
+        };
+        if depth >= MAX_DEPTH {
+            // Reported at the outermost call, rather than with a note for every level
+            self.errors.push((self.map.root(call.span), format!("macro `{}` is nested too deeply, is it recursive?", call.name)));
+            return;
+        }
+        if call.args.len() != def.params.len() {
+            self.errors.push((call.span, format!("macro `{}` takes {} argument(s) but {} were given",
+                                                 call.name, def.params.len(), call.args.len())));
+            return;
+        }
+
+        self.expansions += 1;
+        let text = match substitute(&def, &call.args, self.expansions) {
+            Ok(text) => text,
+            Err(e) => {
+                self.errors.push(e);
+                return;
+            }
+        };
+        let file = self.map.add_expansion(text.clone(), Expansion {
+            macro_name: def.name.clone(),
+            call: call.span,
+            body: def.body_span
+        });
+
+        match parser::parse(file, &text, &call.aliases) {
+            Ok((statements, errors)) => {
+                self.errors.extend(errors);
+                self.expand_statements(statements, depth + 1);
+            },
+            Err(e) => self.errors.push((call.span, format!("invalid expansion of macro `{}`:\n{}", def.name, e)))
+        }
+    }
+}
+
+/// Replaces every macro call with the statements of its expansion, and drops the definitions. Expansions are added
+/// to `map` so that errors in them can be traced back to the call and the macro body.
+pub fn expand(statements: Vec<Statement>, map: &mut SourceMap, errors: &mut Vec<(Span, String)>) -> Vec<Statement> {
+    let mut macros: HashMap<String, MacroDef> = HashMap::new();
+    for statement in &statements {
+        if let Statement::Macro(ref def) = *statement {
+            if macros.contains_key(&def.name) {
+                errors.push((def.span, format!("macro `{}` is defined more than once", def.name)));
+            } else {
+                macros.insert(def.name.clone(), def.clone());
+            }
+        }
+    }
+
+    let mut expander = Expander {
+        macros: macros,
+        map: map,
+        errors: errors,
+        expansions: 0,
+        out: Vec::new()
+    };
+    expander.expand_statements(statements, 0);
+    return expander.out;
+}
+
+#[cfg(test)]
+mod tests {
+    use ::assembler::tests::image;
+
+    #[test]
+    fn expands_a_looping_macro_twice() {
+        let src = ".macro repeat ch, n\n    set $0 \\n\nagain:\n    out \\ch\n    add $0 $0 32767\n    jt $0 :again\n.endm\n\
+                   repeat ',', 2\n    repeat ';', 3   ; comment\n    halt\n";
+        assert_eq!(image(src), vec![
+            1, 32768, 2, 19, 44, 9, 32768, 32768, 32767, 7, 32768, 3,
+            1, 32768, 3, 19, 59, 9, 32768, 32768, 32767, 7, 32768, 15,
+            0
+        ]);
+    }
+
+    #[test]
+    fn keeps_commas_in_string_arguments() {
+        assert_eq!(image(".macro msg s\n.string \\s\n.endm\nmsg \"a, b\"\n"), vec![97, 44, 32, 98, 0]);
+    }
+
+    #[test]
+    fn local_labels_belong_to_each_expansion() {
+        let src = ".macro wait n\n    set $0 \\n\n.loop:\n    add $0 $0 32767\n    jt $0 :.loop\n.endm\n\
+                   main:\n    wait 3\n.loop:\n    wait 2\n    jmp :.loop\n";
+        assert_eq!(image(src), vec![
+            1, 32768, 3,
+            9, 32768, 32768, 32767,
+            7, 32768, 3,
+            1, 32768, 2,
+            9, 32768, 32768, 32767,
+            7, 32768, 13,
+            6, 10
+        ]);
+    }
+
+    #[test]
+    fn numeric_labels_belong_to_each_expansion() {
+        let src = ".macro skip\n    jmp :1f\n    out 'x'\n1:\n.endm\n1:  skip\n    skip\n    jmp :1b\n";
+        assert_eq!(image(src), vec![6, 4, 19, 120, 6, 8, 19, 120, 6, 0]);
+    }
+
+    #[test]
+    fn numeric_references_without_a_label_in_the_body_leave_the_macro() {
+        let src = ".macro back\n    jmp :1b\n.endm\n1:  out 'a'\n    back\n";
+        assert_eq!(image(src), vec![19, 97, 6, 0]);
+    }
+}
+
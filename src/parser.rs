@@ -1,6 +1,10 @@
-use ::assembler::ProgramElement;
-use ::instruction::{Instruction, Register, Parameter};
+use ::assembler::{ProgramElement, SourceElement};
+use ::instruction::{BinOp, Expr, Instruction, Register, Parameter};
+use ::macros::{self, MacroCall, MacroDef};
+use ::pseudo::Pseudo;
+use ::source::Span;
 
+use std::collections::HashMap;
 use std::str::{self};
 
 use pest;
@@ -17,67 +21,436 @@ const _GRAMMAR: &'static str = include_str!("synasm.pest");
 struct AsmParser;
 
 
-fn parse_param(p: Pair<Rule, StrInput>) -> Parameter {
+/// A value the grammar accepts but that can't be assembled, like a literal that doesn't fit in a word.
+pub type ParseError = (Span, String);
+
+/// The register aliases defined with `.reg` that are live at some point in a file.
+pub type Aliases = HashMap<String, Register>;
+
+/// Everything in a source file: program elements, and the macros that still have to be expanded into more of them.
+#[derive(Debug)]
+pub enum Statement {
+    Element(SourceElement),
+    Macro(MacroDef),
+    MacroCall(MacroCall),
+    /// `.include "file"`, replaced with the statements of that file.
+    Include { span: Span, path: String },
+    /// `.incbin "file"`, replaced with the words in that file.
+    Incbin { span: Span, path: String },
+    Pseudo { span: Span, operands: Vec<Span>, pseudo: Pseudo },
+    /// `.scratch $6, $7`, the registers pseudo-instructions after it may overwrite.
+    Scratch { span: Span, registers: Vec<Register> },
+    /// `.reg name $r`, kept so that the assembler can check that no constant has the same name.
+    Alias { span: Span, name: String }
+}
+
+/// Spans are created in file 0; `parse` moves them to the file being parsed.
+/// The span of `p`. Rules that end in a repetition also take in the whitespace and comments after their last token,
+/// so spans of rules with inner tokens end at the last one, or at a closing parenthesis after it.
+fn span_of(p: &Pair<Rule, StrInput>) -> Span {
+    let span = p.clone().into_span();
+    let end = match p.clone().into_inner().last() {
+        Some(last) => {
+            let last_end = span_of(&last).end;
+            let mut end = last_end;
+            let mut in_comment = false;
+            for (i, c) in p.as_str()[(last_end - span.start())..].char_indices() {
+                match c {
+                    ';' => in_comment = true,
+                    '\n' => in_comment = false,
+                    ')' if !in_comment => end = last_end + i + 1,
+                    _ => {}
+                }
+            }
+            end
+        },
+        None => span.end()
+    };
+    Span { file: 0, start: span.start(), end: end }
+}
+
+fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_macro(pair: Pair<Rule, StrInput>, file: usize) -> Result<MacroDef, ParseError> {
+    let span = Span { file: file, ..span_of(&pair) };
+    let text = pair.as_str();
+
+    // The body runs from the end of the `.macro` line up to the line with `.endm`
+    let header_len = text.find('\n').unwrap();
+    let body_len = text.rfind('\n').unwrap();
+    let header = text[".macro".len()..header_len].split(';').next().unwrap();
+
+    let mut words = header.split(|c: char| c == ',' || c.is_whitespace()).filter(|w| !w.is_empty());
+    let name = words.next().unwrap_or("");
+    let params: Vec<String> = words.map(|w| w.to_string()).collect();
+    if !is_ident(name) || !params.iter().all(|p| is_ident(p)) {
+        return Err((Span { end: span.start + header_len, ..span }, "invalid macro definition, expected `.macro name arg, ...`".to_string()));
+    }
+
+    return Ok(MacroDef {
+        name: name.to_string(),
+        params: params,
+        body: text[header_len..body_len].to_string(),
+        body_span: Span { file: file, start: span.start + header_len, end: span.start + body_len },
+        span: span
+    });
+}
+
+fn parse_macro_call(pair: Pair<Rule, StrInput>, file: usize, aliases: &Aliases) -> MacroCall {
+    let text = pair.as_str().trim_right();
+    let name_len = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(text.len());
+    let args = text[name_len..].trim();
+    let span = span_of(&pair);
+
+    return MacroCall {
+        span: Span { file: file, start: span.start, end: span.start + text.len() },
+        name: text[..name_len].to_string(),
+        args: if args.is_empty() { Vec::new() } else { macros::split_args(args) },
+        aliases: aliases.clone()
+    };
+}
+
+fn parse_literal(p: Pair<Rule, StrInput>) -> Result<u16, ParseError> {
     match p.as_rule() {
-        Rule::int_literal => Parameter::Literal(p.as_str().parse::<u16>().unwrap()),
-        Rule::hex_literal => Parameter::Literal(u16::from_str_radix(&p.as_str()[1..], 16).unwrap()),
-        Rule::char_literal => Parameter::Literal(p.as_str().chars().nth(1).unwrap() as u16),
-        Rule::reg_ref => Parameter::Register(parse_reg(p)),
-        Rule::label_ref => Parameter::Label(p.as_str()[1..].to_string()),
+        Rule::int_literal => p.as_str().parse::<u16>()
+            .map_err(|_| (span_of(&p), format!("literal {} is out of range (0-65535)", p.as_str()))),
+        Rule::hex_literal => {
+            let s = p.as_str();
+            let digits = if s.starts_with("0x") { &s[2..] } else { &s[1..] };
+            Ok(u16::from_str_radix(digits, 16).unwrap())
+        },
+        Rule::char_literal => {
+            let c = p.as_str().chars().nth(1).unwrap();
+            if c as u32 > 32767 {
+                return Err((span_of(&p), format!("character {:?} is outside 0..32767", c)));
+            }
+            Ok(c as u16)
+        },
+        _ => panic!()
+    }
+}
+
+fn parse_op(p: Pair<Rule, StrInput>) -> BinOp {
+    match p.as_str() {
+        "+" => BinOp::Add,
+        "-" => BinOp::Sub,
+        "*" => BinOp::Mul,
+        "<<" => BinOp::Shl,
+        ">>" => BinOp::Shr,
+        "&" => BinOp::And,
+        "|" => BinOp::Or,
         _ => panic!()
     }
 }
 
-fn parse_reg(p: Pair<Rule, StrInput>) -> Register {
-    Register(p.into_span().as_str()[1..].parse::<u8>().unwrap())
+/// Parses an expression. A register alias on its own becomes a register term, which `parse_param` unwraps.
+fn parse_expr(p: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Expr, ParseError> {
+    match p.as_rule() {
+        Rule::expr | Rule::expr_and | Rule::expr_shift | Rule::expr_add | Rule::expr_mul => {
+            let mut inner = p.into_inner();
+            let first = inner.next().unwrap();
+            let mut lhs_span = span_of(&first);
+            let mut lhs = parse_expr(first, aliases)?;
+            while let Some(op) = inner.next() {
+                let next = inner.next().unwrap();
+                let rhs_span = span_of(&next);
+                let rhs = parse_expr(next, aliases)?;
+                for &(e, span) in &[(&lhs, lhs_span), (&rhs, rhs_span)] {
+                    if let Expr::Term(Parameter::Register(ref r)) = *e {
+                        return Err((span, format!("register {} can't be used in an expression", r)));
+                    }
+                }
+                lhs = Expr::Binary(parse_op(op), Box::new(lhs), Box::new(rhs));
+                lhs_span.end = rhs_span.end;
+            }
+            Ok(lhs)
+        },
+        Rule::label_ref => Ok(Expr::Term(Parameter::Label(p.as_str()[1..].to_string()))),
+        Rule::const_ref => match aliases.get(p.as_str()) {
+            Some(r) => Ok(Expr::Term(Parameter::Register(r.clone()))),
+            None => Ok(Expr::Term(Parameter::Constant(p.as_str().to_string())))
+        },
+        _ => Ok(Expr::Term(Parameter::Literal(parse_literal(p)?)))
+    }
+}
+
+fn parse_param(p: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Parameter, ParseError> {
+    match p.as_rule() {
+        Rule::reg_ref => Ok(Parameter::Register(parse_reg(p, aliases)?)),
+        _ => match parse_expr(p, aliases)? {
+            Expr::Term(t) => Ok(t),
+            e => Ok(Parameter::Expr(Box::new(e)))
+        }
+    }
 }
 
-fn parse_instruction(pair: Pair<Rule, StrInput>) -> Instruction {
+/// Parses an operand that has to be known at assembly time, so it can't be a register.
+fn parse_value(p: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Parameter, ParseError> {
+    let span = span_of(&p);
+    match parse_param(p, aliases)? {
+        Parameter::Register(r) => Err((span, format!("expected a value, but this is register {}", r))),
+        v => Ok(v)
+    }
+}
+
+fn unescape(p: Pair<Rule, StrInput>) -> String {
+    let s = p.as_str();
+    let mut res = String::new();
+    let mut chars = s[1..(s.len()-1)].chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next().unwrap() {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c => c
+            }
+        } else { c };
+        res.push(c);
+    }
+    res
+}
+
+fn parse_string(p: Pair<Rule, StrInput>) -> Result<Vec<u16>, ParseError> {
+    let span = span_of(&p);
+    let s = unescape(p);
+    match s.chars().find(|c| *c as u32 > 32767) {
+        Some(c) => Err((span, format!("character {:?} is outside 0..32767", c))),
+        None => Ok(s.chars().map(|c| c as u16).collect())
+    }
+}
+
+fn parse_directive(pair: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<ProgramElement, ParseError> {
     let rule = pair.as_rule();
 
     let mut inner = pair.into_inner();
 
     match rule {
+        Rule::dir_equ => {
+            let name = inner.next().unwrap().as_str().to_string();
+            Ok(ProgramElement::Constant(name, parse_value(inner.next().unwrap(), aliases)?))
+        },
+        Rule::dir_org => Ok(ProgramElement::Org(parse_value(inner.next().unwrap(), aliases)?)),
+        Rule::dir_align => Ok(ProgramElement::Align(parse_value(inner.next().unwrap(), aliases)?)),
+        Rule::dir_word => Ok(ProgramElement::Data(inner.map(parse_literal).collect::<Result<_, _>>()?)),
+        Rule::dir_zero => Ok(ProgramElement::Data(vec![0; parse_literal(inner.next().unwrap())? as usize])),
+        Rule::dir_string => {
+            let mut s = parse_string(inner.next().unwrap())?;
+            s.push(0);
+            Ok(ProgramElement::Data(s))
+        },
+        Rule::dir_pstring => {
+            let mut s = parse_string(inner.next().unwrap())?;
+            let len = s.len() as u16;
+            s.insert(0, len);
+            Ok(ProgramElement::Data(s))
+        },
+        _ => panic!()
+    }
+}
+
+fn parse_reg(p: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Register, ParseError> {
+    match p.as_rule() {
+        Rule::reg_ref => Ok(Register(p.into_span().as_str()[1..].parse::<u8>().unwrap())),
+        _ => aliases.get(p.as_str()).cloned()
+            .ok_or_else(|| (span_of(&p), format!("`{}` is not a register or a register alias", p.as_str())))
+    }
+}
+
+fn parse_instruction(pair: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Instruction, ParseError> {
+    let rule = pair.as_rule();
+
+    let mut inner = pair.into_inner();
+
+    Ok(match rule {
         Rule::ins_halt => Instruction::Halt,
-        Rule::ins_set => Instruction::Set(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_push => Instruction::Push(parse_param(inner.next().unwrap())),
-        Rule::ins_pop => Instruction::Pop(parse_reg(inner.next().unwrap())),
-        Rule::ins_eq => Instruction::Eq(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_gt => Instruction::Gt(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_jmp => Instruction::Jmp(parse_param(inner.next().unwrap())),
-        Rule::ins_jt => Instruction::Jt(parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_jf => Instruction::Jf(parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_add => Instruction::Add(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_mult => Instruction::Mult(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_mod => Instruction::Mod(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_and => Instruction::And(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_or => Instruction::Or(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_not => Instruction::Not(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_rmem => Instruction::Rmem(parse_reg(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_wmem => Instruction::Wmem(parse_param(inner.next().unwrap()), parse_param(inner.next().unwrap())),
-        Rule::ins_call => Instruction::Call(parse_param(inner.next().unwrap())),
+        Rule::ins_set => Instruction::Set(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_push => Instruction::Push(parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_pop => Instruction::Pop(parse_reg(inner.next().unwrap(), aliases)?),
+        Rule::ins_eq => Instruction::Eq(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_gt => Instruction::Gt(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_jmp => Instruction::Jmp(parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_jt => Instruction::Jt(parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_jf => Instruction::Jf(parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_add => Instruction::Add(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_mult => Instruction::Mult(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_mod => Instruction::Mod(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_and => Instruction::And(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_or => Instruction::Or(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_not => Instruction::Not(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_rmem => Instruction::Rmem(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_wmem => Instruction::Wmem(parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_call => Instruction::Call(parse_param(inner.next().unwrap(), aliases)?),
         Rule::ins_ret => Instruction::Ret,
-        Rule::ins_out => Instruction::Out(parse_param(inner.next().unwrap())),
-        Rule::ins_in => Instruction::In(parse_reg(inner.next().unwrap())),
+        Rule::ins_out => Instruction::Out(parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ins_in => Instruction::In(parse_reg(inner.next().unwrap(), aliases)?),
         Rule::ins_noop => Instruction::Noop,
         Rule::ins_dmp => Instruction::Dmp,
         _ => panic!()
-    }
+    })
+}
+
+fn parse_pseudo(pair: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<Pseudo, ParseError> {
+    let rule = pair.as_rule();
+
+    let mut inner = pair.into_inner();
+
+    Ok(match rule {
+        Rule::ps_sub => Pseudo::Sub(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ps_xor => Pseudo::Xor(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        // The count becomes a factor for `mult`, so it has to be known at assembly time
+        Rule::ps_shl => Pseudo::Shl(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?, parse_value(inner.next().unwrap(), aliases)?),
+        Rule::ps_inc => Pseudo::Inc(parse_reg(inner.next().unwrap(), aliases)?),
+        Rule::ps_dec => Pseudo::Dec(parse_reg(inner.next().unwrap(), aliases)?),
+        Rule::ps_neg => Pseudo::Neg(parse_reg(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        Rule::ps_movmem => Pseudo::MovMem(parse_param(inner.next().unwrap(), aliases)?, parse_param(inner.next().unwrap(), aliases)?),
+        _ => panic!()
+    })
 }
 
-pub fn parse_elem(pair: Pair<Rule, StrInput>) -> ProgramElement {
+pub fn parse_elem(pair: Pair<Rule, StrInput>, aliases: &Aliases) -> Result<ProgramElement, ParseError> {
     match pair.as_rule() {
-        Rule::instruction => ProgramElement::Instruction(parse_instruction(pair.into_inner().next().unwrap())),
+        Rule::instruction => Ok(ProgramElement::Instruction(parse_instruction(pair.into_inner().next().unwrap(), aliases)?)),
+        Rule::directive => parse_directive(pair.into_inner().next().unwrap(), aliases),
         Rule::label_def => {
             let s = pair.as_str();
-            ProgramElement::Label(s[..(s.len()-1)].to_string())
+            Ok(ProgramElement::Label(s[..(s.len()-1)].to_string()))
         }
         _ => panic!()
     }
 }
 
-pub fn parse(src: &str) -> Result<Vec<ProgramElement>, pest::Error<Rule, StrInput>> {
+/// Whether a label starts a new routine, which gets its own register aliases and local labels.
+fn is_global_label(name: &str) -> bool {
+    !name.starts_with('.') && !name.contains('@') && !name.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Parses a whole file, giving all spans the file id `file`. Elements with invalid values are left out and
+/// reported in the second list, so that every problem in a file can be reported at once.
+/// `aliases` are the register aliases in scope at the start of the file, and again after every global label.
+pub fn parse<'a>(file: usize, src: &'a str, aliases: &Aliases)
+                 -> Result<(Vec<Statement>, Vec<ParseError>), pest::Error<Rule, StrInput<'a>>> {
     let pairs: Pairs<Rule, pest::inputs::StrInput> = AsmParser::parse_str(Rule::main, src)?;
+    let in_file = |span: Span| Span { file: file, ..span };
+
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    let mut scope = aliases.clone();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::label_def if is_global_label(pair.as_str().trim_right_matches(':')) => {
+                scope = aliases.clone();
+                statements.push(Statement::Element(SourceElement {
+                    span: in_file(span_of(&pair)),
+                    operands: Vec::new(),
+                    elem: ProgramElement::Label(pair.as_str().trim_right_matches(':').to_string()),
+                    generated: false
+                }));
+            },
+            Rule::dir_reg => {
+                let span = in_file(span_of(&pair));
+                let mut inner = pair.into_inner();
+                let name = inner.next().unwrap().as_str().to_string();
+                let reg = parse_reg(inner.next().unwrap(), &scope).unwrap();
+                if let Some(other) = scope.iter().find(|&(n, r)| *r == reg && *n != name).map(|(n, _)| n.clone()) {
+                    errors.push((span, format!("{} already has the alias `{}` in this routine", reg, other)));
+                } else if scope.get(&name).map_or(false, |r| *r != reg) {
+                    errors.push((span, format!("alias `{}` is already defined in this routine", name)));
+                } else if scope.insert(name.clone(), reg).is_none() {
+                    statements.push(Statement::Alias { span: span, name: name });
+                }
+            },
+            Rule::macro_def => match parse_macro(pair, file) {
+                Ok(def) => statements.push(Statement::Macro(def)),
+                Err(e) => errors.push(e)
+            },
+            Rule::macro_call => statements.push(Statement::MacroCall(parse_macro_call(pair, file, &scope))),
+            Rule::dir_scratch => {
+                let span = in_file(span_of(&pair));
+                match pair.into_inner().map(|p| parse_reg(p, &scope)).collect() {
+                    Ok(registers) => statements.push(Statement::Scratch { span: span, registers: registers }),
+                    Err((span, msg)) => errors.push((in_file(span), msg))
+                }
+            },
+            Rule::pseudo => {
+                let span = in_file(span_of(&pair));
+                let inner = pair.into_inner().next().unwrap();
+                let operands = inner.clone().into_inner().map(|p| in_file(span_of(&p))).collect();
+                match parse_pseudo(inner, &scope) {
+                    Ok(pseudo) => statements.push(Statement::Pseudo { span: span, operands: operands, pseudo: pseudo }),
+                    Err((span, msg)) => errors.push((in_file(span), msg))
+                }
+            },
+            Rule::dir_include | Rule::dir_incbin => {
+                let span = in_file(span_of(&pair));
+                let rule = pair.as_rule();
+                let path = unescape(pair.into_inner().next().unwrap());
+                statements.push(if rule == Rule::dir_include {
+                    Statement::Include { span: span, path: path }
+                } else {
+                    Statement::Incbin { span: span, path: path }
+                });
+            },
+            _ => {
+                let span = in_file(span_of(&pair));
+                let operands = match pair.as_rule() {
+                    Rule::instruction | Rule::directive => pair.clone().into_inner().next().unwrap().into_inner()
+                        .map(|p| in_file(span_of(&p))).collect(),
+                    _ => Vec::new()
+                };
+                match parse_elem(pair, &scope) {
+                    Ok(elem) => statements.push(Statement::Element(SourceElement {
+                        span: span,
+                        operands: operands,
+                        elem: elem,
+                        generated: false
+                    })),
+                    Err((span, msg)) => errors.push((in_file(span), msg))
+                }
+            }
+        }
+    }
+    Ok((statements, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::assembler::{Assembler, AssemblerError};
+    use ::assembler::tests::{errors, image};
+
+    #[test]
+    fn aliases_reset_at_global_labels() {
+        assert_eq!(image("a:\n.reg x $1\n    set x 5\n.loop:\n    set x 6\nb:\n.reg x $2\n    set x 7"),
+                   vec![1, 32769, 5, 1, 32769, 6, 1, 32770, 7]);
+        assert_eq!(errors("a:\n.reg x $1\nb:\n    set x 7"), vec!["`x` is not a register or a register alias"]);
+    }
+
+    #[test]
+    fn rejects_duplicate_aliases() {
+        assert_eq!(errors(".reg x $1\n.reg y $1"), vec!["$1 already has the alias `x` in this routine"]);
+        assert_eq!(errors(".reg x $1\n.reg x $2"), vec!["alias `x` is already defined in this routine"]);
+        assert_eq!(image(".reg x $1\n.reg x $1\nset x 1"), vec![1, 32769, 1]);
+    }
+
+    #[test]
+    fn rejects_aliases_named_like_constants() {
+        assert_eq!(errors(".equ N 5\n.reg N $0\n    out N"), vec!["alias `N` has the same name as a constant"]);
+
+        let mut asm = Assembler::new();
+        asm.define("N", 5);
+        match asm.assemble("test.synasm", ".reg N $0\n    out N") {
+            Err(AssemblerError::Diagnostics(d)) => assert_eq!(d[0].message, "alias `N` has the same name as a constant"),
+            _ => panic!("expected an error")
+        }
+    }
+
+    #[test]
+    fn scratch_takes_aliases() {
+        assert_eq!(image(".reg t $7\n.scratch t\n    movmem 1 2"), vec![15, 32775, 2, 16, 1, 32775]);
+        assert_eq!(image(".scratch $6, $7\n.scratch\nfoo:\n    halt"), vec![0]);
+    }
+}
 
-    Ok(pairs.map(parse_elem).collect())
-}
\ No newline at end of file
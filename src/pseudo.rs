@@ -0,0 +1,225 @@
+use ::assembler::{ProgramElement, SourceElement};
+use ::instruction::{BinOp, Expr, Instruction, Parameter, Register};
+use ::parser::Statement;
+use ::source::Span;
+
+/// Instructions the architecture doesn't have, which the assembler lowers into real ones.
+/// Some of them need scratch registers, which have to be declared with `.scratch` first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pseudo {
+    Sub(Register, Parameter, Parameter),
+    Xor(Register, Parameter, Parameter),
+    Shl(Register, Parameter, Parameter),
+    Inc(Register),
+    Dec(Register),
+    Neg(Register, Parameter),
+    MovMem(Parameter, Parameter)
+}
+
+impl Pseudo {
+    pub fn mnemonic(&self) -> &'static str {
+        match *self {
+            Pseudo::Sub(..) => "sub",
+            Pseudo::Xor(..) => "xor",
+            Pseudo::Shl(..) => "shl",
+            Pseudo::Inc(_) => "inc",
+            Pseudo::Dec(_) => "dec",
+            Pseudo::Neg(..) => "neg",
+            Pseudo::MovMem(..) => "movmem"
+        }
+    }
+
+    /// Every operand in source order, with register targets wrapped in `Parameter::Register`.
+    fn operands(&self) -> Vec<Parameter> {
+        let reg = |r: &Register| Parameter::Register(r.clone());
+        match *self {
+            Pseudo::Sub(ref a, ref b, ref c) |
+            Pseudo::Xor(ref a, ref b, ref c) |
+            Pseudo::Shl(ref a, ref b, ref c) => vec![reg(a), b.clone(), c.clone()],
+            Pseudo::Inc(ref a) | Pseudo::Dec(ref a) => vec![reg(a)],
+            Pseudo::Neg(ref a, ref b) => vec![reg(a), b.clone()],
+            Pseudo::MovMem(ref a, ref b) => vec![a.clone(), b.clone()]
+        }
+    }
+
+    fn scratch_needed(&self) -> usize {
+        match *self {
+            Pseudo::Xor(..) => 2,
+            Pseudo::MovMem(..) => 1,
+            // Subtracting a register needs a temporary, which can be the target unless it's also the minuend
+            Pseudo::Sub(ref a, ref b, Parameter::Register(_)) if *b == Parameter::Register(a.clone()) => 1,
+            _ => 0
+        }
+    }
+
+    fn lower(&self, scratch: &[Register]) -> Vec<Instruction> {
+        let reg = |r: &Register| Parameter::Register(r.clone());
+        match *self {
+            Pseudo::Inc(ref a) => vec![Instruction::Add(a.clone(), reg(a), Parameter::Literal(1))],
+            Pseudo::Dec(ref a) => vec![Instruction::Add(a.clone(), reg(a), Parameter::Literal(32767))],
+            Pseudo::Neg(ref a, ref b) => vec![
+                Instruction::Not(a.clone(), b.clone()),
+                Instruction::Add(a.clone(), reg(a), Parameter::Literal(1))
+            ],
+            Pseudo::Sub(ref a, ref b, Parameter::Register(ref c)) => {
+                let t = if self.scratch_needed() > 0 { scratch[0].clone() } else { a.clone() };
+                vec![
+                    Instruction::Not(t.clone(), reg(c)),
+                    Instruction::Add(t.clone(), reg(&t), Parameter::Literal(1)),
+                    Instruction::Add(a.clone(), b.clone(), reg(&t))
+                ]
+            },
+            Pseudo::Sub(ref a, ref b, ref c) => vec![Instruction::Add(a.clone(), b.clone(), negate(c))],
+            Pseudo::Xor(ref a, ref b, ref c) => {
+                let (s, t) = (&scratch[0], &scratch[1]);
+                vec![
+                    Instruction::Not(s.clone(), c.clone()),
+                    Instruction::And(s.clone(), b.clone(), reg(s)),
+                    Instruction::Not(t.clone(), b.clone()),
+                    Instruction::And(t.clone(), reg(t), c.clone()),
+                    Instruction::Or(a.clone(), reg(s), reg(t))
+                ]
+            },
+            Pseudo::Shl(ref a, ref b, ref n) => {
+                let factor = match *n {
+                    Parameter::Literal(n) if n < 15 => Parameter::Literal(1 << n),
+                    Parameter::Literal(_) => Parameter::Literal(0),
+                    _ => Parameter::Expr(Box::new(shift_factor(n)))
+                };
+                vec![Instruction::Mult(a.clone(), b.clone(), factor)]
+            },
+            Pseudo::MovMem(ref dst, ref src) => vec![
+                Instruction::Rmem(scratch[0].clone(), src.clone()),
+                Instruction::Wmem(dst.clone(), reg(&scratch[0]))
+            ]
+        }
+    }
+}
+
+/// `32768 - x`, which added to a number subtracts `x` from it.
+fn negate(x: &Parameter) -> Parameter {
+    match *x {
+        Parameter::Literal(x) => Parameter::Literal((32768 - (x as u32 % 32768)) as u16 % 32768),
+        _ => {
+            let diff = Expr::Binary(BinOp::Sub, Box::new(Expr::Term(Parameter::Literal(32768))), Box::new(Expr::Term(x.clone())));
+            Parameter::Expr(Box::new(Expr::Binary(BinOp::And, Box::new(diff), Box::new(Expr::Term(Parameter::Literal(32767))))))
+        }
+    }
+}
+
+/// `(1 << (n & 15)) & (32767 >> (n & 65520))`, which is `1 << n` modulo 32768 for any count: the left side
+/// is 0 for 15, and the right side is 0 for 16 and up, without shifting by more than the evaluator allows.
+fn shift_factor(n: &Parameter) -> Expr {
+    let lit = |x| Box::new(Expr::Term(Parameter::Literal(x)));
+    let masked = |mask| Box::new(Expr::Binary(BinOp::And, Box::new(Expr::Term(n.clone())), lit(mask)));
+    Expr::Binary(BinOp::And,
+                 Box::new(Expr::Binary(BinOp::Shl, lit(1), masked(15))),
+                 Box::new(Expr::Binary(BinOp::Shr, lit(32767), masked(65520))))
+}
+
+/// Replaces every pseudo-instruction with the real instructions it stands for, and drops `.scratch` and `.reg`.
+/// Generated operands that come straight from the pseudo-instruction keep their span, for error messages.
+pub fn lower(statements: Vec<Statement>, errors: &mut Vec<(Span, String)>) -> Vec<SourceElement> {
+    let mut scratch: Vec<Register> = Vec::new();
+    let mut out = Vec::new();
+
+    for statement in statements {
+        match statement {
+            Statement::Element(elem) => out.push(elem),
+            Statement::Scratch { registers, .. } => scratch = registers,
+            Statement::Alias { .. } => {},
+            Statement::Pseudo { span, operands, pseudo } => {
+                let params = pseudo.operands();
+                let needed = pseudo.scratch_needed();
+                if scratch.len() < needed {
+                    errors.push((span, format!("`{}` needs {} scratch register(s) here, declare them with .scratch",
+                                               pseudo.mnemonic(), needed)));
+                    continue;
+                }
+                if let Some(r) = scratch[..needed].iter().find(|r| params.contains(&Parameter::Register((*r).clone()))) {
+                    errors.push((span, format!("scratch register {} can't be an operand of `{}`", r, pseudo.mnemonic())));
+                    continue;
+                }
+
+                for instr in pseudo.lower(&scratch[..needed]) {
+                    let spans = instr.operands().iter()
+                        .map(|p| params.iter().position(|q| q == p).map(|i| operands[i]).unwrap_or(span))
+                        .collect();
+                    out.push(SourceElement {
+                        span: span,
+                        operands: spans,
+                        elem: ProgramElement::Instruction(instr),
+                        generated: true
+                    });
+                }
+            },
+            _ => unreachable!("includes and macros are expanded before lowering")
+        }
+    }
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use ::assembler::tests::{errors, image};
+
+    #[test]
+    fn lowers_sub() {
+        assert_eq!(image("sub $0 $1 5"), vec![9, 32768, 32769, 32763]);
+        assert_eq!(image("sub $0 $1 $2"), vec![14, 32768, 32770, 9, 32768, 32768, 1, 9, 32768, 32769, 32768]);
+        assert_eq!(image(".scratch $7\nsub $0 $0 $2"), vec![14, 32775, 32770, 9, 32775, 32775, 1, 9, 32768, 32768, 32775]);
+    }
+
+    #[test]
+    fn lowers_xor() {
+        assert_eq!(image(".scratch $6, $7\nxor $0 $1 $2"), vec![
+            14, 32774, 32770,
+            12, 32774, 32769, 32774,
+            14, 32775, 32769,
+            12, 32775, 32775, 32770,
+            13, 32768, 32774, 32775
+        ]);
+    }
+
+    #[test]
+    fn lowers_shl() {
+        assert_eq!(image("shl $0 $1 3"), vec![10, 32768, 32769, 8]);
+        assert_eq!(image(".equ N 4\nshl $0 $1 N"), vec![10, 32768, 32769, 16]);
+    }
+
+    #[test]
+    fn shifts_everything_out_past_14() {
+        assert_eq!(image("shl $0 $1 14\nshl $0 $1 15\nshl $0 $1 65535"), vec![
+            10, 32768, 32769, 16384,
+            10, 32768, 32769, 0,
+            10, 32768, 32769, 0
+        ]);
+        for &(n, factor) in &[(14, 16384), (15, 0), (16, 0), (47, 0), (48, 0), (65535, 0)] {
+            assert_eq!(image(&format!(".equ N {}\nshl $0 $1 N", n)), vec![10, 32768, 32769, factor]);
+        }
+    }
+
+    #[test]
+    fn lowers_inc_dec_neg() {
+        assert_eq!(image("inc $3\ndec $3"), vec![9, 32771, 32771, 1, 9, 32771, 32771, 32767]);
+        assert_eq!(image("neg $0 $1"), vec![14, 32768, 32769, 9, 32768, 32768, 1]);
+    }
+
+    #[test]
+    fn lowers_movmem() {
+        assert_eq!(image(".scratch $7\nmovmem 100 200"), vec![15, 32775, 200, 16, 100, 32775]);
+    }
+
+    #[test]
+    fn rejects_missing_or_conflicting_scratch() {
+        assert_eq!(errors("xor $0 $1 $2"), vec!["`xor` needs 2 scratch register(s) here, declare them with .scratch"]);
+        assert_eq!(errors(".scratch $6, $7\nxor $0 $1 $7"), vec!["scratch register $7 can't be an operand of `xor`"]);
+        assert_eq!(errors(".scratch $7\n.scratch\nmovmem 1 2"), vec!["`movmem` needs 1 scratch register(s) here, declare them with .scratch"]);
+    }
+
+    #[test]
+    fn rejects_register_shift_count() {
+        assert_eq!(errors("shl $0 $1 $2"), vec!["expected a value, but this is register $2"]);
+        assert_eq!(errors(".reg n $2\nshl $0 $1 n"), vec!["expected a value, but this is register $2"]);
+    }
+}
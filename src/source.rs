@@ -0,0 +1,242 @@
+use std::{cmp, fmt, mem};
+
+/// A byte range in one of the files of a `SourceMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize
+}
+
+/// Where the text of a macro expansion came from.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub macro_name: String,
+    /// The macro call that was expanded.
+    pub call: Span,
+    /// The macro body in its definition. Line `n` of the expansion is line `n` of the body.
+    pub body: Span
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub name: String,
+    pub src: String,
+    /// Set for the text generated by expanding a macro, which doesn't exist on disk.
+    pub expansion: Option<Expansion>,
+    /// The `.include` directive that pulled this file in.
+    pub included_at: Option<Span>
+}
+
+/// Every piece of source text that went into a program, so that spans can be traced back to real files.
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    files: Vec<SourceFile>
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        return SourceMap {
+            files: Vec::new()
+        };
+    }
+
+    pub fn add_file(&mut self, name: &str, src: String) -> usize {
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            src: src,
+            expansion: None,
+            included_at: None
+        });
+        self.files.len() - 1
+    }
+
+    pub fn add_include(&mut self, name: &str, src: String, included_at: Span) -> usize {
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            src: src,
+            expansion: None,
+            included_at: Some(included_at)
+        });
+        self.files.len() - 1
+    }
+
+    pub fn add_expansion(&mut self, src: String, expansion: Expansion) -> usize {
+        self.files.push(SourceFile {
+            name: format!("<macro {}>", expansion.macro_name),
+            src: src,
+            expansion: Some(expansion),
+            included_at: None
+        });
+        self.files.len() - 1
+    }
+
+    pub fn file(&self, id: usize) -> &SourceFile {
+        &self.files[id]
+    }
+
+    /// Follows macro expansions back to the span in a real file that caused `span` to exist.
+    pub fn root(&self, span: Span) -> Span {
+        match self.files[span.file].expansion {
+            Some(ref exp) => self.root(exp.call),
+            None => span
+        }
+    }
+
+    /// Like `root`, but also follows includes back to the file that was passed to the assembler.
+    pub fn main_span(&self, span: Span) -> Span {
+        let span = self.root(span);
+        match self.files[span.file].included_at {
+            Some(at) => self.main_span(at),
+            None => span
+        }
+    }
+
+    /// The 1-based line and column of the start of `span`, along with the file it's in.
+    /// Lines inside macro expansions are given as the matching line of the macro body.
+    fn locate(&self, span: Span) -> (&str, usize, usize) {
+        let file = &self.files[span.file];
+        let (line, col) = line_col(&file.src, span.start);
+        match file.expansion {
+            Some(ref exp) => {
+                let (name, body_line, _) = self.locate(exp.body);
+                (name, body_line + line - 1, col)
+            },
+            None => (&file.name, line, col)
+        }
+    }
+}
+
+/// Returns the 1-based line and column of the byte offset `pos` in `src`.
+pub fn line_col(src: &str, pos: usize) -> (usize, usize) {
+    let before = &src[..pos];
+    let line = before.matches('\n').count() + 1;
+    let col = match before.rfind('\n') {
+        Some(i) => before[(i + 1)..].chars().count() + 1,
+        None => before.chars().count() + 1
+    };
+    (line, col)
+}
+
+/// An error at a specific place in a source file, printed along with the offending line.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+    /// The full source line the error is on. For errors inside a macro, this is the line after expansion.
+    pub excerpt: String,
+    /// How many characters of the excerpt to underline, starting at `col`.
+    pub width: usize,
+    /// The macro calls and includes that led to the error, innermost first, followed by any other places
+    /// the error is about, like the first definition of a duplicate label.
+    pub notes: Vec<Diagnostic>
+}
+
+impl Diagnostic {
+    pub fn new(map: &SourceMap, span: Span, message: String) -> Diagnostic {
+        let src = &map.file(span.file).src;
+        let (file, line, col) = map.locate(span);
+        let excerpt = src.lines().nth(line_col(src, span.start).0 - 1).unwrap_or("").to_string();
+        let rest = excerpt.chars().count().saturating_sub(col - 1);
+        let width = src[span.start..span.end].chars().take_while(|c| *c != '\n').count();
+
+        let origin = match *map.file(span.file) {
+            SourceFile { expansion: Some(ref exp), .. } =>
+                Some(Diagnostic::new(map, exp.call, format!("in expansion of macro `{}`", exp.macro_name))),
+            SourceFile { included_at: Some(at), .. } => Some(Diagnostic::new(map, at, "included from here".to_string())),
+            _ => None
+        };
+        let notes = match origin {
+            Some(mut origin) => {
+                let mut notes = mem::replace(&mut origin.notes, Vec::new());
+                notes.insert(0, origin);
+                notes
+            },
+            None => Vec::new()
+        };
+
+        return Diagnostic {
+            file: file.to_string(),
+            line: line,
+            col: col,
+            message: message,
+            excerpt: excerpt,
+            width: cmp::max(1, cmp::min(width, rest)),
+            notes: notes
+        };
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, kind: &str) -> fmt::Result {
+        let indent: String = self.excerpt.chars().take(self.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        writeln!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.col, kind, self.message)?;
+        writeln!(f, "    {}", self.excerpt)?;
+        write!(f, "    {}{}", indent, "^".repeat(self.width))
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, "error")?;
+        for note in &self.notes {
+            writeln!(f)?;
+            note.write(f, "note")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::{assemble_program, AssemblerError};
+
+    fn diagnostics(src: &str) -> Vec<Diagnostic> {
+        match assemble_program("test.synasm", src) {
+            Err(AssemblerError::Diagnostics(d)) => d,
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("expected errors")
+        }
+    }
+
+    fn location(d: &Diagnostic) -> (&str, usize, usize, &str, usize) {
+        (&d.file, d.line, d.col, &d.excerpt, d.width)
+    }
+
+    #[test]
+    fn locates_undefined_labels() {
+        let d = diagnostics("start: noop\n    jmp :nowhere   ; oops\n");
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].message, "undefined label `nowhere`");
+        assert_eq!(location(&d[0]), ("test.synasm", 2, 9, "    jmp :nowhere   ; oops", 8));
+        assert!(d[0].notes.is_empty());
+        assert_eq!(d[0].to_string(), "test.synasm:2:9: error: undefined label `nowhere`\n    \
+                                      \u{20}   jmp :nowhere   ; oops\n            ^^^^^^^^");
+    }
+
+    #[test]
+    fn underlines_operands_without_the_comment_after_them() {
+        let d = diagnostics("    out (:nowhere + 1)  ; (x)\n");
+        assert_eq!(location(&d[0]), ("test.synasm", 1, 9, "    out (:nowhere + 1)  ; (x)", 14));
+    }
+
+    #[test]
+    fn points_duplicate_labels_at_the_first_definition() {
+        let d = diagnostics("loop: noop\n\n  loop: halt\n");
+        assert_eq!(d.len(), 1);
+        assert_eq!(d[0].message, "label `loop` is defined more than once");
+        assert_eq!(location(&d[0]), ("test.synasm", 3, 3, "  loop: halt", 5));
+        assert_eq!(d[0].notes.len(), 1);
+        assert_eq!(d[0].notes[0].message, "first defined here");
+        assert_eq!(location(&d[0].notes[0]), ("test.synasm", 1, 1, "loop: noop", 5));
+    }
+
+    #[test]
+    fn locates_errors_inside_macros() {
+        let d = diagnostics(".macro go\n    jmp :nowhere\n.endm\n    noop\n    go\n");
+        assert_eq!(location(&d[0]), ("test.synasm", 2, 9, "    jmp :nowhere", 8));
+        assert_eq!(d[0].notes[0].message, "in expansion of macro `go`");
+        assert_eq!(location(&d[0].notes[0]), ("test.synasm", 5, 5, "    go", 2));
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// Label addresses from an assembled program, used to show `swap+3` instead of raw addresses.
+#[derive(Debug, Clone)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_addr: BTreeMap<u16, String>
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        return SymbolTable {
+            by_name: HashMap::new(),
+            by_addr: BTreeMap::new()
+        };
+    }
+
+    pub fn from_labels(labels: &HashMap<String, u16>) -> Self {
+        let mut table = SymbolTable::new();
+        for (name, adr) in labels {
+            table.insert(name, *adr);
+        }
+        return table;
+    }
+
+    /// When several labels share an address, the alphabetically first one is used for symbolizing it.
+    pub fn insert(&mut self, name: &str, adr: u16) {
+        self.by_name.insert(name.to_string(), adr);
+        let replace = match self.by_addr.get(&adr) {
+            Some(existing) => name < existing.as_str(),
+            None => true
+        };
+        if replace {
+            self.by_addr.insert(adr, name.to_string());
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Names `adr` relative to the closest label at or before it, like `swap` or `swap+3`.
+    pub fn symbolize(&self, adr: u16) -> Option<String> {
+        self.by_addr.range(..=adr).next_back().map(|(base, name)| {
+            if *base == adr {
+                name.clone()
+            } else {
+                format!("{}+{}", name, adr - base)
+            }
+        })
+    }
+
+    /// Formats `adr` as `x0123 <swap+3>`, or just `x0123` if there is no label before it.
+    pub fn describe(&self, adr: u16) -> String {
+        match self.symbolize(adr) {
+            Some(s) => format!("x{:04x} <{}>", adr, s),
+            None => format!("x{:04x}", adr)
+        }
+    }
+
+    /// Writes one `x0123 name` line per label, ordered by address.
+    pub fn write(&self, out: &mut Write) -> io::Result<()> {
+        let mut entries: Vec<(&u16, &String)> = self.by_name.iter().map(|(n, a)| (a, n)).collect();
+        entries.sort();
+        for (adr, name) in entries {
+            writeln!(out, "x{:04x} {}", adr, name)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the format written by `write`. Empty lines and lines starting with `;` are skipped.
+    pub fn read(input: &mut Read) -> io::Result<SymbolTable> {
+        let mut table = SymbolTable::new();
+        for line in BufReader::new(input).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let adr = match parts.get(0) {
+                Some(a) if a.starts_with('x') => u16::from_str_radix(&a[1..], 16).ok(),
+                Some(a) => a.parse::<u16>().ok(),
+                None => None
+            };
+            match (adr, parts.get(1)) {
+                (Some(adr), Some(name)) if parts.len() == 2 => table.insert(name, adr),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid symbol line: {}", line)))
+            }
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> SymbolTable {
+        let mut table = SymbolTable::new();
+        table.insert("swap", 0x20);
+        table.insert("main", 0x10);
+        table.insert("alias", 0x20);
+        table
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let mut out = Vec::new();
+        table().write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out.clone()).unwrap(), "x0010 main\nx0020 alias\nx0020 swap\n");
+
+        let read = SymbolTable::read(&mut &out[..]).unwrap();
+        assert_eq!((read.get("main"), read.get("swap"), read.get("alias")), (Some(0x10), Some(0x20), Some(0x20)));
+        assert_eq!(read.symbolize(0x20), Some("alias".to_string()));
+    }
+
+    #[test]
+    fn reads_comments_and_decimal_addresses() {
+        let read = SymbolTable::read(&mut "; labels\n\n  16 main\n".as_bytes()).unwrap();
+        assert_eq!(read.get("main"), Some(16));
+        assert!(SymbolTable::read(&mut "x0010 main extra\n".as_bytes()).is_err());
+        assert!(SymbolTable::read(&mut "main\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn symbolizes_relative_to_the_closest_label() {
+        let table = table();
+        assert_eq!(table.symbolize(0x10), Some("main".to_string()));
+        assert_eq!(table.symbolize(0x13), Some("main+3".to_string()));
+        assert_eq!(table.symbolize(0x25), Some("alias+5".to_string()));
+        assert_eq!(table.symbolize(0x0f), None);
+        assert_eq!(table.describe(0x13), "x0013 <main+3>");
+        assert_eq!(table.describe(0x0f), "x000f");
+    }
+}
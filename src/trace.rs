@@ -0,0 +1,138 @@
+use ::instruction::Instruction;
+use ::symbols::SymbolTable;
+
+use std::io::{self, Write};
+
+/// One executed instruction, as reported to `VM::set_trace_callback`.
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    /// How many instructions were executed before this one.
+    pub index: u64,
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// The value of every operand before execution. Register operands (including write targets) read the register.
+    pub operands: Vec<u16>,
+    /// Register state after execution.
+    pub registers: [u16; 8]
+}
+
+/// Selects which entries end up in a trace. Every kind of filter that has been set must match;
+/// within a kind, any of the ranges or opcodes may match.
+pub struct TraceFilter {
+    ranges: Vec<(u16, u16)>,
+    opcodes: Vec<String>,
+    window: Option<(u64, u64)>
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        return TraceFilter {
+            ranges: Vec::new(),
+            opcodes: Vec::new(),
+            window: None
+        };
+    }
+
+    /// Only trace instructions with `start <= pc <= end`.
+    pub fn add_range(&mut self, start: u16, end: u16) {
+        self.ranges.push((start, end));
+    }
+
+    /// Only trace instructions with this mnemonic, like `call` or `wmem`.
+    pub fn add_opcode(&mut self, mnemonic: &str) {
+        self.opcodes.push(mnemonic.to_string());
+    }
+
+    /// Only trace instructions whose index is in `start..end`.
+    pub fn set_window(&mut self, start: u64, end: u64) {
+        self.window = Some((start, end));
+    }
+
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        if let Some((start, end)) = self.window {
+            if entry.index < start || entry.index >= end {
+                return false;
+            }
+        }
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|&(s, e)| entry.pc >= s && entry.pc <= e) {
+            return false;
+        }
+        if !self.opcodes.is_empty() && !self.opcodes.iter().any(|o| o == entry.instruction.mnemonic()) {
+            return false;
+        }
+        true
+    }
+}
+
+/// Writes an entry as a single line, laid out so that traces of two runs can be compared with `diff`.
+pub fn write_entry(out: &mut Write, entry: &TraceEntry, symbols: &SymbolTable) -> io::Result<()> {
+    let operands: Vec<String> = entry.operands.iter().map(|v| format!("{:04x}", v)).collect();
+    let registers: Vec<String> = entry.registers.iter().map(|v| format!("{:04x}", v)).collect();
+    writeln!(out, "{:>10} {:<28} {:<24} [{}] regs {}",
+             entry.index, symbols.describe(entry.pc), entry.instruction.to_string(), operands.join(" "), registers.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::tests::image;
+    use ::vm::VM;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// The trace of a program that calls `f` at x000a.
+    fn trace() -> Vec<TraceEntry> {
+        let words = image("    set $0 5\n    add $1 $0 2\n    call :f\n    halt\nf:  wmem 100 $1\n    ret\n");
+        let bytes: Vec<u8> = words.iter().flat_map(|w| vec![*w as u8, (*w >> 8) as u8]).collect();
+        let mut vm = VM::new_from_reader(&mut &bytes[..]);
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let sink = entries.clone();
+        vm.set_trace_callback(move |e| sink.borrow_mut().push(e.clone()));
+        vm.execute().unwrap();
+        let res = entries.borrow().clone();
+        res
+    }
+
+    fn traced(filter: &TraceFilter) -> Vec<u64> {
+        trace().iter().filter(|e| filter.matches(e)).map(|e| e.index).collect()
+    }
+
+    #[test]
+    fn filters_by_address_opcode_and_count() {
+        assert_eq!(trace().iter().map(|e| e.pc).collect::<Vec<_>>(), vec![0, 3, 7, 10, 13, 9]);
+        assert_eq!(traced(&TraceFilter::new()), vec![0, 1, 2, 3, 4, 5]);
+
+        let mut ranges = TraceFilter::new();
+        ranges.add_range(3, 7);
+        ranges.add_range(10, 10);
+        assert_eq!(traced(&ranges), vec![1, 2, 3]);
+
+        let mut opcodes = TraceFilter::new();
+        opcodes.add_opcode("wmem");
+        opcodes.add_opcode("ret");
+        assert_eq!(traced(&opcodes), vec![3, 4]);
+
+        let mut window = TraceFilter::new();
+        window.set_window(1, 3);
+        assert_eq!(traced(&window), vec![1, 2]);
+
+        window.set_window(2, 6);
+        window.add_range(0, 9);
+        assert_eq!(traced(&window), vec![2, 5]);
+    }
+
+    #[test]
+    fn writes_operand_values_and_registers() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("f", 10);
+        let entries = trace();
+        let mut out = Vec::new();
+        write_entry(&mut out, &entries[1], &symbols).unwrap();
+        write_entry(&mut out, &entries[3], &symbols).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().collect::<Vec<_>>(), vec![
+            "         1 x0003                        add $1 $0 2              [0000 0005 0002] regs 0005 0007 0000 0000 0000 0000 0000 0000",
+            "         3 x000a <f>                    wmem 100 $1              [0064 0007] regs 0005 0007 0000 0000 0000 0000 0000 0000"
+        ]);
+    }
+}
@@ -0,0 +1,392 @@
+use ::analysis::{self, is_terminator};
+use ::instruction::{Instruction, Parameter, Register};
+
+use std::cmp;
+use std::io::{self, Write};
+
+fn reg(r: &Register) -> String {
+    format!("s.r[{}]", r.0)
+}
+
+fn value(p: &Parameter) -> String {
+    match *p {
+        Parameter::Literal(x) => x.to_string(),
+        Parameter::Register(ref r) => reg(r),
+        _ => unreachable!("decoded instructions only have literals and registers")
+    }
+}
+
+/// Like `value`, but literals are typed so that methods can be called on them.
+fn receiver(p: &Parameter) -> String {
+    match *p {
+        Parameter::Literal(x) => format!("{}u16", x),
+        _ => value(p)
+    }
+}
+
+/// The Rust statements for one instruction. Branches are written as the `Option<u16>` the block returns.
+fn translate_instruction(adr: u16, instr: &Instruction, block: usize) -> String {
+    let next = adr + instr.len();
+    match *instr {
+        Instruction::Halt => "None".to_string(),
+        Instruction::Set(ref a, ref b) => format!("{} = {};", reg(a), value(b)),
+        Instruction::Push(ref a) => format!("s.stack.push({});", value(a)),
+        Instruction::Pop(ref a) => format!("{} = s.pop();", reg(a)),
+        Instruction::Eq(ref a, ref b, ref c) => format!("{} = ({} == {}) as u16;", reg(a), value(b), value(c)),
+        Instruction::Gt(ref a, ref b, ref c) => format!("{} = ({} > {}) as u16;", reg(a), value(b), value(c)),
+        Instruction::Jmp(ref a) => format!("Some({})", value(a)),
+        Instruction::Jt(ref a, ref b) => format!("if {} != 0 {{ Some({}) }} else {{ Some({}) }}", value(a), value(b), next),
+        Instruction::Jf(ref a, ref b) => format!("if {} == 0 {{ Some({}) }} else {{ Some({}) }}", value(a), value(b), next),
+        Instruction::Add(ref a, ref b, ref c) => format!("{} = {}.wrapping_add({}) % 32768;", reg(a), receiver(b), value(c)),
+        Instruction::Mult(ref a, ref b, ref c) => format!("{} = {}.wrapping_mul({}) % 32768;", reg(a), receiver(b), value(c)),
+        Instruction::Mod(ref a, ref b, ref c) => format!("{} = s.rem({}, {}, {}) % 32768;", reg(a), value(b), value(c), adr),
+        Instruction::And(ref a, ref b, ref c) => format!("{} = ({} & {}) % 32768;", reg(a), value(b), value(c)),
+        Instruction::Or(ref a, ref b, ref c) => format!("{} = ({} | {}) % 32768;", reg(a), value(b), value(c)),
+        Instruction::Not(ref a, ref b) => format!("{} = !{} % 32768;", reg(a), value(b)),
+        Instruction::Rmem(ref a, ref b) => format!("{} = s.read({}, {});", reg(a), value(b), adr),
+        // The rest of the block may just have been overwritten, in which case the interpreter takes over
+        Instruction::Wmem(ref a, ref b) => format!("s.write({}, {}, {}); if s.dirty[{}] {{ return Some({}); }}",
+                                                   value(a), value(b), adr, block, next),
+        Instruction::Call(ref a) => format!("s.stack.push({}); Some({})", next, value(a)),
+        Instruction::Ret => "Some(s.pop())".to_string(),
+        Instruction::Out(ref a) => format!("s.output({});", value(a)),
+        Instruction::In(ref a) => format!("{} = s.input();", reg(a)),
+        Instruction::Noop => String::new(),
+        Instruction::Dmp => format!("s.dump({});", adr)
+    }
+}
+
+/// Writes a standalone Rust program that runs `memory` like `vm::VM` does, with every basic block found by
+/// `analysis::find_blocks` compiled to a function. Computed jumps and returns go through a dispatch table, and code that
+/// wasn't found or has since been overwritten runs in an interpreter. `name` is mentioned in a comment.
+/// Only the first 32768 words of `memory` are translated, since the VM can't address the rest.
+pub fn translate(out: &mut Write, memory: &[u16], name: &str) -> io::Result<()> {
+    let memory = &memory[..cmp::min(memory.len(), 32768)];
+    let blocks = analysis::find_blocks(memory);
+    let len = memory.iter().rposition(|w| *w != 0).map_or(0, |i| i + 1);
+
+    writeln!(out, "// Translated from {} by syntrans. Build it with `rustc -O`.", name)?;
+    writeln!(out, "#![allow(unused_parens, unused_variables)]")?;
+    writeln!(out, "{}", RUNTIME)?;
+
+    writeln!(out, "static IMAGE: [u16; {}] = [", len)?;
+    for row in memory[..len].chunks(16) {
+        let words: Vec<String> = row.iter().map(|w| format!("{:#06x}", w)).collect();
+        writeln!(out, "    {},", words.join(", "))?;
+    }
+    writeln!(out, "];\n")?;
+
+    writeln!(out, "static BLOCKS: [(u16, u16); {}] = [", blocks.len())?;
+    for block in &blocks {
+        writeln!(out, "    ({:#06x}, {:#06x}),", block.start, block.end())?;
+    }
+    writeln!(out, "];\n")?;
+
+    writeln!(out, "fn dispatch(s: &mut State, pc: u16) -> Option<u16> {{")?;
+    writeln!(out, "    match pc {{")?;
+    for (i, block) in blocks.iter().enumerate() {
+        writeln!(out, "        {:#06x} if !s.dirty[{}] => block_{:04x}(s),", block.start, i, block.start)?;
+    }
+    writeln!(out, "        _ => s.step(pc)")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+
+    for (i, block) in blocks.iter().enumerate() {
+        writeln!(out, "\nfn block_{:04x}(s: &mut State) -> Option<u16> {{", block.start)?;
+        for &(adr, ref instr) in &block.instructions {
+            let code = translate_instruction(adr, instr, i);
+            writeln!(out, "    {:<60} // x{:04x}: {}", code, adr, instr)?;
+        }
+        if let Some(next) = block.fallthrough {
+            if !is_terminator(&block.instructions.last().unwrap().1) {
+                writeln!(out, "    Some({:#06x})", next)?;
+            }
+        }
+        writeln!(out, "}}")?;
+    }
+    Ok(())
+}
+
+/// The part of every translated program that doesn't depend on the binary: the machine state, the interpreter
+/// and `main`. Errors are reported like `synvm` reports them.
+const RUNTIME: &'static str = r#"
+use std::char;
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::process;
+
+const MEM: usize = 32768;
+
+struct State {
+    r: [u16; 8],
+    mem: Vec<u16>,
+    stack: Vec<u16>,
+    /// Which words belong to a translated block.
+    code: Vec<bool>,
+    /// Blocks whose code has been overwritten, which run in the interpreter from then on.
+    dirty: Vec<bool>,
+    /// The rest of the last line read from stdin, a character per word.
+    input: VecDeque<u16>,
+    out: BufWriter<Stdout>
+}
+
+impl State {
+    fn new() -> State {
+        let mut mem = vec![0u16; MEM];
+        mem[..IMAGE.len()].copy_from_slice(&IMAGE);
+        let mut code = vec![false; MEM];
+        for &(start, end) in BLOCKS.iter() {
+            for adr in start..end {
+                code[adr as usize] = true;
+            }
+        }
+        State {
+            r: [0; 8],
+            mem: mem,
+            stack: Vec::new(),
+            code: code,
+            dirty: vec![false; BLOCKS.len()],
+            input: VecDeque::new(),
+            out: BufWriter::new(io::stdout())
+        }
+    }
+
+    fn fail(&mut self, msg: String) -> ! {
+        let _ = self.out.flush();
+        println!("Error: {}", msg);
+        process::exit(1);
+    }
+
+    fn pop(&mut self) -> u16 {
+        match self.stack.pop() {
+            Some(v) => v,
+            None => self.fail("Popped from empty stack".to_string())
+        }
+    }
+
+    fn read(&mut self, adr: u16, pc: u16) -> u16 {
+        if adr as usize >= MEM {
+            self.fail(format!("Memory address {} out of bounds at x{:04x}", adr, pc));
+        }
+        self.mem[adr as usize]
+    }
+
+    fn write(&mut self, adr: u16, v: u16, pc: u16) {
+        if adr as usize >= MEM {
+            self.fail(format!("Memory address {} out of bounds at x{:04x}", adr, pc));
+        }
+        if self.code[adr as usize] && self.mem[adr as usize] != v {
+            for (i, &(start, end)) in BLOCKS.iter().enumerate() {
+                if start <= adr && adr < end {
+                    self.dirty[i] = true;
+                }
+            }
+        }
+        self.mem[adr as usize] = v;
+    }
+
+    fn rem(&mut self, a: u16, b: u16, pc: u16) -> u16 {
+        if b == 0 {
+            self.fail(format!("Division by zero at x{:04x}", pc));
+        }
+        a % b
+    }
+
+    fn output(&mut self, v: u16) {
+        let c = char::from_u32(v as u32).expect("Cannot convert to char");
+        write!(self.out, "{}", c).expect("Unable to write to stdout");
+    }
+
+    /// The next character of stdin, or 0 once it's exhausted. Stdin is read a line at a time like `synvm` reads it,
+    /// and lines with a character above U+7FFF are rejected.
+    fn input(&mut self) -> u16 {
+        self.out.flush().expect("Unable to write to stdout");
+        while self.input.is_empty() {
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) => return 0,
+                Ok(_) => {},
+                Err(_) => self.fail("Unable to read from stdin".to_string())
+            }
+            match line.chars().find(|c| *c as u32 > 32767) {
+                Some(c) => println!("Character {:?} can't be input, it's outside 0..32767, try again", c),
+                None => self.input.extend(line.chars().map(|c| c as u16))
+            }
+        }
+        self.input.pop_front().unwrap()
+    }
+
+    fn dump(&mut self, pc: u16) {
+        let _ = self.out.flush();
+        println!("At: x{:04x}", pc);
+        println!("Registers: {:?}", self.r);
+        println!("Stack: {:?}", self.stack);
+        println!("Memory (40xx): {:?}", &self.mem[0x4000..0x4100]);
+        println!("Memory (410x): {:?}", &self.mem[0x4100..0x4110]);
+        println!("Memory (50xx): {:?}", &self.mem[0x5000..0x5100]);
+        println!("Memory (60xx): {:?}", &self.mem[0x6000..0x6100]);
+        println!("-----");
+    }
+
+    /// Runs the instruction at `pc` without translating it, for code that wasn't found or has been overwritten.
+    fn step(&mut self, pc: u16) -> Option<u16> {
+        let p = pc as usize;
+        if p >= MEM {
+            self.fail(format!("Instruction at x{:04x} runs past the end of memory", pc));
+        }
+        let op = self.mem[p];
+        let len = match op {
+            0 | 18 | 21 | 255 => 1,
+            2 | 3 | 6 | 17 | 19 | 20 => 2,
+            1 | 7 | 8 | 14 | 15 | 16 => 3,
+            4 | 5 | 9 | 10 | 11 | 12 | 13 => 4,
+            _ => self.fail(format!("Unknown instruction {}", op))
+        };
+        if p + len > MEM {
+            self.fail(format!("Instruction at x{:04x} runs past the end of memory", pc));
+        }
+        let mut w = [0u16; 3];
+        for i in 1..len {
+            w[i - 1] = self.mem[p + i];
+            if w[i - 1] > 32775 {
+                self.fail(format!("Invalid operand {} in instruction at x{:04x}", w[i - 1], pc));
+            }
+        }
+        // These write their result to the register in the first operand
+        let has_target = match op {
+            1 | 3 | 4 | 5 | 9 | 10 | 11 | 12 | 13 | 14 | 15 | 20 => true,
+            _ => false
+        };
+        if has_target && w[0] < 32768 {
+            self.fail(format!("Invalid operand {} in instruction at x{:04x}", w[0], pc));
+        }
+
+        let t = (w[0] as usize).wrapping_sub(32768);
+        let mut v = [0u16; 3];
+        for i in 0..3 {
+            v[i] = if w[i] < 32768 { w[i] } else { self.r[(w[i] - 32768) as usize] };
+        }
+        let next = pc + len as u16;
+        match op {
+            0 => return None,
+            1 => self.r[t] = v[1],
+            2 => self.stack.push(v[0]),
+            3 => self.r[t] = self.pop(),
+            4 => self.r[t] = (v[1] == v[2]) as u16,
+            5 => self.r[t] = (v[1] > v[2]) as u16,
+            6 => return Some(v[0]),
+            7 => if v[0] != 0 { return Some(v[1]); },
+            8 => if v[0] == 0 { return Some(v[1]); },
+            9 => self.r[t] = v[1].wrapping_add(v[2]) % 32768,
+            10 => self.r[t] = v[1].wrapping_mul(v[2]) % 32768,
+            11 => self.r[t] = self.rem(v[1], v[2], pc) % 32768,
+            12 => self.r[t] = (v[1] & v[2]) % 32768,
+            13 => self.r[t] = (v[1] | v[2]) % 32768,
+            14 => self.r[t] = !v[1] % 32768,
+            15 => self.r[t] = self.read(v[1], pc),
+            16 => self.write(v[0], v[1], pc),
+            17 => {
+                self.stack.push(next);
+                return Some(v[0]);
+            },
+            18 => return Some(self.pop()),
+            19 => self.output(v[0]),
+            20 => self.r[t] = self.input(),
+            255 => self.dump(pc),
+            _ => {}
+        }
+        Some(next)
+    }
+}
+
+fn main() {
+    let mut s = State::new();
+    let mut pc = 0u16;
+    while let Some(next) = dispatch(&mut s, pc) {
+        pc = next;
+    }
+    let _ = s.out.flush();
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::tests::image;
+    use ::vm::{VM, VMError};
+    use std::cell::RefCell;
+    use std::char;
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::process::{self, Command, Stdio};
+    use std::rc::Rc;
+
+    fn translated(memory: &[u16]) -> String {
+        let mut out = Vec::new();
+        translate(&mut out, memory, "test.bin").unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn translates_calls_computed_jumps_and_writes() {
+        let src = translated(&image("    call :f\n    jmp $0\nf:  set $0 :g\n    wmem :f 21\n    ret\ng:  out 'x'\n    halt\n"));
+
+        assert!(src.contains("static IMAGE: [u16; 13] = ["));
+        assert!(src.contains("static BLOCKS: [(u16, u16); 3] = ["));
+        for &(adr, i) in &[(0, 0), (2, 1), (4, 2)] {
+            assert!(src.contains(&format!("{:#06x} if !s.dirty[{}] => block_{:04x}(s),", adr, i, adr)));
+        }
+        // `g` is only reached through `jmp $0`, so the interpreter runs it
+        assert!(!src.contains("block_000b"));
+        assert!(src.contains("_ => s.step(pc)"));
+
+        assert!(src.contains("s.stack.push(2); Some(4)"));
+        assert!(src.contains("Some(s.r[0])"));
+        assert!(src.contains("s.r[0] = 11;"));
+        assert!(src.contains("s.write(4, 21, 7); if s.dirty[2] { return Some(10); }"));
+        assert!(src.contains("Some(s.pop())"));
+    }
+
+    #[test]
+    fn ignores_words_past_memory() {
+        let src = translated(&vec![21; 40000]);
+        assert!(src.contains("static IMAGE: [u16; 32768] = ["));
+    }
+
+    fn run_translated(exe: &Path, input: &str) -> String {
+        let mut child = Command::new(exe).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        String::from_utf8(child.wait_with_output().unwrap().stdout).unwrap()
+    }
+
+    fn run_vm(memory: &[u16], input: &str) -> String {
+        let out = Rc::new(RefCell::new(String::new()));
+        let sink = out.clone();
+        let bytes: Vec<u8> = memory.iter().flat_map(|w| vec![*w as u8, (*w >> 8) as u8]).collect();
+        let mut vm = VM::new_from_reader(&mut &bytes[..]);
+        vm.set_output_callback(move |v| sink.borrow_mut().push(char::from_u32(v as u32).unwrap()));
+        vm.provide_input(input).unwrap();
+        vm.execute().unwrap();
+        let s = out.borrow().clone();
+        s
+    }
+
+    #[test]
+    fn reads_input_like_the_vm() {
+        let memory = image("    in $1\n    out $1\n    in $1\n    out $1\n    halt\n");
+        let dir = env::temp_dir().join(format!("rustacor-translate-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (src, exe) = (dir.join("echo.rs"), dir.join("echo"));
+        File::create(&src).unwrap().write_all(translated(&memory).as_bytes()).unwrap();
+        assert!(Command::new("rustc").arg(&src).arg("-o").arg(&exe).status().unwrap().success());
+
+        let echoed = run_translated(&exe, "é\n");
+        let rejected = run_translated(&exe, "\u{8000}\nab\n");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(echoed, run_vm(&memory, "é\n"));
+        assert_eq!(echoed, "é\n");
+        assert_eq!(rejected, format!("{}, try again\nab", VMError::InvalidInput('\u{8000}')));
+    }
+}
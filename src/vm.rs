@@ -1,20 +1,279 @@
-use ::instruction::{Instruction, Parameter, Register};
-use std::io::Read;
-use byteorder::{LittleEndian, ReadBytesExt};
+use ::instruction::{DecodeError, Instruction, Parameter};
+use ::symbols::SymbolTable;
+use ::trace::TraceEntry;
+use std::collections::{BTreeSet, VecDeque};
+use std::error::Error;
+use std::{fmt, mem};
+use std::io::{self, Read, Write};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 
 pub struct VM<'a> {
     pc: u16,
     registers: [u16; 8],
     memory: [u16; 32768],
     stack: Vec<u16>,
-    input_callback: Box<FnMut() -> u16 + 'a>,
-    output_callback: Box<FnMut(u16) + 'a>
+    steps: u64,
+    halted: bool,
+    symbols: SymbolTable,
+    history: VecDeque<UndoStep>,
+    history_limit: usize,
+    /// What the instruction being executed has changed so far, if history is enabled.
+    changes: Vec<Change>,
+    /// The decoded instruction starting at every address, or `NOT_DECODED`.
+    cache: Vec<Op>,
+    use_cache: bool,
+    watchpoints: Vec<(usize, Watchpoint)>,
+    next_watchpoint: usize,
+    /// The watchpoints the last executed instruction triggered.
+    watch_hits: Vec<WatchHit>,
+    /// Hits of watchpoints set to only log, since the last `take_watch_log`.
+    watch_log: Vec<WatchHit>,
+    /// Input given with `provide_input`, which is read before asking the input callback.
+    input: VecDeque<u16>,
+    input_callback: Option<Box<FnMut() -> u16 + 'a>>,
+    output_callback: Box<FnMut(u16) + 'a>,
+    trace_callback: Option<Box<FnMut(&TraceEntry) + 'a>>
 }
 
+/// Why `VM::run_until` returned.
+#[derive(Debug)]
+pub enum StopReason {
+    Halted,
+    /// The next instruction is an `in`, and there is no input left. It runs once more is given with `provide_input`.
+    NeedsInput,
+    /// The next instruction is at an address `run_until` was told to stop at.
+    Breakpoint(u16),
+    /// The instruction just executed triggered a watchpoint that isn't set to only log.
+    Watchpoint(WatchHit),
+    StepLimit,
+    Error(VMError)
+}
+
+/// An instruction decoded into the form the interpreter runs: the opcode and the raw operand words, which have
+/// been checked to be valid. Register operands are still 32768..32775.
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    opcode: u16,
+    /// How many words the instruction takes up. 0 marks a cache entry that hasn't been decoded.
+    len: u16,
+    args: [u16; 3]
+}
+
+const NOT_DECODED: Op = Op { opcode: 0, len: 0, args: [0; 3] };
+const MAX_INSTRUCTION_LEN: u16 = 4;
+
+impl Op {
+    fn from_instruction(instr: &Instruction) -> Op {
+        let mut args = [0u16; 3];
+        for (i, p) in instr.operands().iter().enumerate() {
+            args[i] = match *p {
+                Parameter::Literal(x) => x,
+                Parameter::Register(ref r) => 32768 + r.0 as u16,
+                _ => unreachable!("decoded instructions only have literals and registers")
+            };
+        }
+        return Op {
+            opcode: instr.idx(),
+            len: instr.len(),
+            args: args
+        };
+    }
+}
+
+/// A value an instruction overwrote, recorded so that it can be put back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// A register and its previous value.
+    Register(u8, u16),
+    /// A memory address and its previous value.
+    Memory(u16, u16),
+    Push,
+    /// A value that was popped off the stack.
+    Pop(u16)
+}
+
+/// Everything one executed instruction changed, for `VM::step_back`. Input read and output written aren't undone.
+#[derive(Debug, Clone)]
+pub struct UndoStep {
+    pub index: u64,
+    pub pc: u16,
+    pub changes: Vec<Change>
+}
+
+/// Which accesses to memory a watchpoint triggers on. Only `rmem` and `wmem` count as accesses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    /// Writes that store a different value than was there before.
+    Change
+}
+
+/// A test of the value read or written, which a watchpoint only triggers if it passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Equal(u16),
+    NotEqual(u16),
+    Less(u16),
+    Greater(u16)
+}
+
+impl Condition {
+    pub fn matches(&self, v: u16) -> bool {
+        match *self {
+            Condition::Equal(x) => v == x,
+            Condition::NotEqual(x) => v != x,
+            Condition::Less(x) => v < x,
+            Condition::Greater(x) => v > x
+        }
+    }
+}
+
+/// Watches the memory words `start..=end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+    /// Collects hits for `take_watch_log` and keeps running, instead of stopping `run_until` and the debugger.
+    pub log: bool
+}
+
+/// An access that triggered a watchpoint. For reads, `old` and `value` are both the value read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub index: u64,
+    pub pc: u16,
+    pub address: u16,
+    pub kind: WatchKind,
+    pub old: u16,
+    pub value: u16
+}
+
+/// A copy of everything that determines how a program continues: `pc`, registers, memory and stack.
+#[derive(Clone)]
+pub struct Snapshot {
+    pc: u16,
+    registers: [u16; 8],
+    memory: Vec<u16>,
+    stack: Vec<u16>
+}
+
+const SNAPSHOT_MAGIC: &'static [u8; 4] = b"SYNS";
+const SNAPSHOT_VERSION: u16 = 1;
+
+impl Snapshot {
+    /// Writes the snapshot as little-endian words: the `SYNS` magic, a format version,
+    /// `pc`, the 8 registers, all 32768 memory words, the stack depth as a u32 and finally the stack, bottom first.
+    pub fn write(&self, out: &mut Write) -> io::Result<()> {
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_u16::<LittleEndian>(SNAPSHOT_VERSION)?;
+        out.write_u16::<LittleEndian>(self.pc)?;
+        for v in self.registers.iter().chain(self.memory.iter()) {
+            out.write_u16::<LittleEndian>(*v)?;
+        }
+        out.write_u32::<LittleEndian>(self.stack.len() as u32)?;
+        for v in &self.stack {
+            out.write_u16::<LittleEndian>(*v)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(input: &mut Read) -> io::Result<Snapshot> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid("Not a snapshot file"));
+        }
+        if input.read_u16::<LittleEndian>()? != SNAPSHOT_VERSION {
+            return Err(invalid("Unsupported snapshot version"));
+        }
+
+        let pc = input.read_u16::<LittleEndian>()?;
+        let mut registers = [0u16; 8];
+        input.read_u16_into::<LittleEndian>(&mut registers)?;
+        let mut memory = vec![0u16; 32768];
+        input.read_u16_into::<LittleEndian>(&mut memory)?;
+        // The depth is checked against what's left of the file, so a corrupt one can't make us allocate gigabytes
+        let depth = input.read_u32::<LittleEndian>()? as usize;
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest)?;
+        if rest.len() as u64 != depth as u64 * 2 {
+            return Err(invalid("Stack depth doesn't match the size of the snapshot"));
+        }
+        let mut stack = vec![0u16; depth];
+        LittleEndian::read_u16_into(&rest, &mut stack);
+
+        return Ok(Snapshot {
+            pc: pc,
+            registers: registers,
+            memory: memory,
+            stack: stack
+        });
+    }
+}
+
+#[derive(Debug)]
 pub enum VMError {
     PopFromEmptyStack,
     UnknownInstruction(u16),
-    OOBRegister(u16)
+    OOBRegister(u16),
+    /// `pc` (or the end of the instruction at `pc`) is past the end of memory.
+    PcOutOfBounds(u16),
+    /// An operand word of the instruction at `pc` is above 32775, or a literal where a register is required.
+    InvalidOperand { pc: u16, word: u16 },
+    MemoryOutOfBounds { pc: u16, instruction: Instruction, address: u16 },
+    DivisionByZero { pc: u16, instruction: Instruction },
+    /// A character given to `provide_input` that doesn't fit in 0..32767.
+    InvalidInput(char)
+}
+
+impl VMError {
+    /// The address of the faulting instruction, for errors that know it.
+    pub fn pc(&self) -> Option<u16> {
+        match *self {
+            VMError::PcOutOfBounds(pc) |
+            VMError::InvalidOperand { pc, .. } => Some(pc),
+            VMError::MemoryOutOfBounds { pc, .. } |
+            VMError::DivisionByZero { pc, .. } => Some(pc),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VMError::PopFromEmptyStack => write!(f, "Popped from empty stack"),
+            VMError::UnknownInstruction(i) => write!(f, "Unknown instruction {}", i),
+            VMError::OOBRegister(i) => write!(f, "Unknown register access {}", i),
+            VMError::PcOutOfBounds(pc) => write!(f, "Instruction at x{:04x} runs past the end of memory", pc),
+            VMError::InvalidOperand { pc, word } => write!(f, "Invalid operand {} in instruction at x{:04x}", word, pc),
+            VMError::MemoryOutOfBounds { pc, ref instruction, address } =>
+                write!(f, "Memory address {} out of bounds at x{:04x}: {}", address, pc, instruction),
+            VMError::DivisionByZero { pc, ref instruction } => write!(f, "Division by zero at x{:04x}: {}", pc, instruction),
+            VMError::InvalidInput(c) => write!(f, "Character {:?} can't be input, it's outside 0..32767", c)
+        }
+    }
+}
+
+impl Error for VMError {
+    fn description(&self) -> &str {
+        match *self {
+            VMError::PopFromEmptyStack => "popped from empty stack",
+            VMError::UnknownInstruction(_) => "unknown instruction",
+            VMError::OOBRegister(_) => "unknown register access",
+            VMError::PcOutOfBounds(_) => "pc out of bounds",
+            VMError::InvalidOperand { .. } => "invalid operand",
+            VMError::MemoryOutOfBounds { .. } => "memory access out of bounds",
+            VMError::DivisionByZero { .. } => "division by zero",
+            VMError::InvalidInput(_) => "input outside 0..32767"
+        }
+    }
 }
 
 impl<'a> VM<'a> {
@@ -24,8 +283,22 @@ impl<'a> VM<'a> {
             registers: [0; 8],
             memory: [0; 32768],
             stack: Vec::new(),
-            input_callback: Box::new(|| 0),
-            output_callback: Box::new(|_| {})
+            steps: 0,
+            halted: false,
+            symbols: SymbolTable::new(),
+            history: VecDeque::new(),
+            history_limit: 0,
+            changes: Vec::new(),
+            cache: vec![NOT_DECODED; 32768],
+            use_cache: true,
+            watchpoints: Vec::new(),
+            next_watchpoint: 1,
+            watch_hits: Vec::new(),
+            watch_log: Vec::new(),
+            input: VecDeque::new(),
+            input_callback: None,
+            output_callback: Box::new(|_| {}),
+            trace_callback: None
         };
     }
 
@@ -41,162 +314,384 @@ impl<'a> VM<'a> {
         return vm;
     }
 
+    /// Calls `f` for input once the input given with `provide_input` runs out. Without a callback, `in` reads 0
+    /// when there's no input left, and `run_until` stops before it instead.
     pub fn set_input_callback<F: 'a>(&mut self, f: F) where F: FnMut() -> u16 {
-        self.input_callback = Box::new(f);
+        self.input_callback = Some(Box::new(f));
+    }
+
+    /// Queues the characters of `s` for `in` to read, one per `in`. Nothing is queued if `s` has a character
+    /// above U+7FFF, since `in` can't produce it.
+    pub fn provide_input(&mut self, s: &str) -> Result<(), VMError> {
+        if let Some(c) = s.chars().find(|c| *c as u32 > 32767) {
+            return Err(VMError::InvalidInput(c));
+        }
+        self.input.extend(s.chars().map(|c| c as u16));
+        Ok(())
     }
 
     pub fn set_output_callback<F: 'a>(&mut self, f: F) where F: FnMut(u16) {
         self.output_callback = Box::new(f);
     }
 
-    fn next_word(&mut self) -> u16 {
-        let v = self.memory[self.pc as usize];
-        self.pc += 1;
-        return v;
-    }
-
-    fn load_instruction(&mut self) -> Result<Instruction, VMError> {
-        let instr = self.next_word();
-        match instr {
-            0 => Ok(Instruction::Halt),
-            1 => Ok(Instruction::Set(self.next_word().into(), self.next_word().into())),
-            2 => Ok(Instruction::Push(self.next_word().into())),
-            3 => Ok(Instruction::Pop(self.next_word().into())),
-            4 => Ok(Instruction::Eq(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            5 => Ok(Instruction::Gt(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            6 => Ok(Instruction::Jmp(self.next_word().into())),
-            7 => Ok(Instruction::Jt(self.next_word().into(), self.next_word().into())),
-            8 => Ok(Instruction::Jf(self.next_word().into(), self.next_word().into())),
-            9 => Ok(Instruction::Add(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            10 => Ok(Instruction::Mult(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            11 => Ok(Instruction::Mod(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            12 => Ok(Instruction::And(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            13 => Ok(Instruction::Or(self.next_word().into(), self.next_word().into(), self.next_word().into())),
-            14 => Ok(Instruction::Not(self.next_word().into(), self.next_word().into())),
-            15 => Ok(Instruction::Rmem(self.next_word().into(), self.next_word().into())),
-            16 => Ok(Instruction::Wmem(self.next_word().into(), self.next_word().into())),
-            17 => Ok(Instruction::Call(self.next_word().into())),
-            18 => Ok(Instruction::Ret),
-            19 => Ok(Instruction::Out(self.next_word().into())),
-            20 => Ok(Instruction::In(self.next_word().into())),
-            21 => Ok(Instruction::Noop),
-            0xff => Ok(Instruction::Dmp),
-            _ => Err(VMError::UnknownInstruction(instr))
-        }
-    }
-
-    fn get_register(&self, reg: &Register) -> Result<u16, VMError> {
-        if reg.0 >= 8 {return Err(VMError::OOBRegister(reg.0 as u16))}
-        return Ok(self.registers[reg.0 as usize]);
-    }
-
-    fn set_register(&mut self, reg: &Register, v: u16) -> Result<(), VMError> {
-        if reg.0 >= 8 {return Err(VMError::OOBRegister(reg.0 as u16))}
-        self.registers[reg.0 as usize] = v;
-        Ok(())
+    /// Calls `f` after every successfully executed instruction.
+    pub fn set_trace_callback<F: 'a>(&mut self, f: F) where F: FnMut(&TraceEntry) {
+        self.trace_callback = Some(Box::new(f));
+    }
+
+    pub fn clear_trace_callback(&mut self) {
+        self.trace_callback = None;
+    }
+
+    /// Labels used to name addresses in the output of `dmp`.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
     }
 
-    fn get_parameter(&self, param: &Parameter) -> Result<u16, VMError> {
-        match *param {
-            Parameter::Register(ref r) => self.get_register(r),
-            Parameter::Literal(l) => Ok(l),
-            _ => panic!("Unsupported parameter type {:?}", param)
+    /// Keeps an undo log of the last `limit` instructions, so they can be reversed with `step_back`.
+    /// A limit of 0 turns the log off, which is the default.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
         }
     }
 
+    /// The undo log, oldest instruction first.
+    pub fn history(&self) -> &VecDeque<UndoStep> {
+        &self.history
+    }
+
+    /// Undoes the last executed instruction. Returns `false` if there is nothing left in the undo log.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.pop_back() {
+            Some(step) => step,
+            None => return false
+        };
+        for change in step.changes.iter().rev() {
+            match *change {
+                Change::Register(r, v) => self.registers[r as usize] = v,
+                Change::Memory(adr, v) => {
+                    self.memory[adr as usize] = v;
+                    self.invalidate(adr);
+                },
+                Change::Push => { self.stack.pop(); },
+                Change::Pop(v) => self.stack.push(v)
+            }
+        }
+        self.pc = step.pc;
+        self.steps = step.index;
+        self.halted = false;
+        self.watch_hits.clear();
+        return true;
+    }
+
+    /// The most recent instruction in the undo log that changed something matching `f`.
+    pub fn last_change<F>(&self, f: F) -> Option<&UndoStep> where F: Fn(&Change) -> bool {
+        self.history.iter().rev().find(|step| step.changes.iter().any(|c| f(c)))
+    }
+
+    /// Adds a watchpoint and returns its id. Ids count up from 1 and aren't reused.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.push((id, watchpoint));
+        return id;
+    }
+
+    /// Returns `false` if there was no watchpoint with this id.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|&(i, _)| i != id);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoint(&self, id: usize) -> Option<&Watchpoint> {
+        self.watchpoints.iter().find(|&&(i, _)| i == id).map(|&(_, ref w)| w)
+    }
+
+    pub fn watchpoints(&self) -> &[(usize, Watchpoint)] {
+        &self.watchpoints
+    }
+
+    /// The watchpoints triggered by the last executed instruction, in the order they were added.
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        &self.watch_hits
+    }
+
+    fn check_watchpoints(&mut self, pc: u16, kind: WatchKind, address: u16, old: u16, value: u16) {
+        for &(id, ref w) in &self.watchpoints {
+            let triggered = match (w.kind, kind) {
+                (WatchKind::Read, WatchKind::Read) | (WatchKind::Write, WatchKind::Write) => true,
+                (WatchKind::Change, WatchKind::Write) => old != value,
+                _ => false
+            };
+            if triggered && w.start <= address && address <= w.end && w.condition.map_or(true, |c| c.matches(value)) {
+                let hit = WatchHit {
+                    id: id,
+                    index: self.steps - 1,
+                    pc: pc,
+                    address: address,
+                    kind: kind,
+                    old: old,
+                    value: value
+                };
+                if w.log {
+                    self.watch_log.push(hit);
+                }
+                self.watch_hits.push(hit);
+            }
+        }
+    }
+
+    /// The first watchpoint hit by the last executed instruction that should stop execution.
+    fn watch_stop(&self) -> Option<WatchHit> {
+        self.watch_hits.iter().find(|hit| self.watchpoint(hit.id).map_or(false, |w| !w.log)).cloned()
+    }
+
+    /// Returns the hits of watchpoints set to only log since the last call. They are collected however the VM
+    /// is run, so callers that set such watchpoints should take them regularly.
+    pub fn take_watch_log(&mut self) -> Vec<WatchHit> {
+        mem::replace(&mut self.watch_log, Vec::new())
+    }
+
+    fn record(&mut self, change: Change) {
+        if self.history_limit > 0 {
+            self.changes.push(change);
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// How many instructions have been executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Whether the last executed instruction was a `halt`.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn registers(&self) -> &[u16; 8] {
+        &self.registers
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn memory(&self) -> &[u16] {
+        &self.memory
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        return Snapshot {
+            pc: self.pc,
+            registers: self.registers,
+            memory: self.memory.to_vec(),
+            stack: self.stack.clone()
+        };
+    }
+
+    /// Also clears the undo log, since it describes how the previous state came about.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.history.clear();
+        self.watch_hits.clear();
+        self.halted = false;
+        self.clear_cache();
+        self.pc = snapshot.pc;
+        self.registers = snapshot.registers;
+        self.memory.copy_from_slice(&snapshot.memory);
+        self.stack = snapshot.stack.clone();
+    }
+
+    /// Turns the decoded instruction cache on or off. It's on by default; turning it off decodes every
+    /// instruction again each time it runs.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.use_cache = enabled;
+        self.clear_cache();
+    }
+
+    fn clear_cache(&mut self) {
+        for op in self.cache.iter_mut() {
+            *op = NOT_DECODED;
+        }
+    }
+
+    /// Decodes the instruction at `pc` without executing it.
+    pub fn current_instruction(&self) -> Result<Instruction, VMError> {
+        let pc = self.pc as usize;
+        if pc >= self.memory.len() {
+            return Err(VMError::PcOutOfBounds(self.pc));
+        }
+        Instruction::decode(&self.memory[pc..]).map_err(|e| match e {
+            DecodeError::UnknownOpcode(i) => VMError::UnknownInstruction(i),
+            DecodeError::Truncated => VMError::PcOutOfBounds(self.pc),
+            DecodeError::InvalidOperand { word, .. } => VMError::InvalidOperand { pc: self.pc, word: word }
+        })
+    }
+
+    /// The instruction at `pc` in decoded form, from the cache if it has been run before.
+    fn fetch(&mut self) -> Result<Op, VMError> {
+        let pc = self.pc as usize;
+        if self.use_cache && pc < self.cache.len() && self.cache[pc].len > 0 {
+            return Ok(self.cache[pc]);
+        }
+        let op = Op::from_instruction(&self.current_instruction()?);
+        if self.use_cache {
+            self.cache[pc] = op;
+        }
+        Ok(op)
+    }
+
+    /// Forgets the decoded instructions that contain the word at `address`, after it has changed.
+    fn invalidate(&mut self, address: u16) {
+        let start = address.saturating_sub(MAX_INSTRUCTION_LEN - 1) as usize;
+        for op in &mut self.cache[start..(address as usize + 1)] {
+            *op = NOT_DECODED;
+        }
+    }
+
+    /// The value of an operand word, which is either a literal or a register.
+    fn value(&self, w: u16) -> u16 {
+        if w < 32768 { w } else { self.registers[(w - 32768) as usize] }
+    }
+
+    fn set_register(&mut self, w: u16, v: u16) {
+        let r = (w - 32768) as usize;
+        let old = self.registers[r];
+        self.record(Change::Register(r as u8, old));
+        self.registers[r] = v;
+    }
+
     fn stack_push(&mut self, v: u16) {
+        self.record(Change::Push);
         self.stack.push(v);
     }
 
     fn stack_pop(&mut self) -> Result<u16, VMError> {
         return match self.stack.pop() {
-            Some(x) => Ok(x),
+            Some(x) => {
+                self.record(Change::Pop(x));
+                Ok(x)
+            },
             None => Err(VMError::PopFromEmptyStack)
         };
     }
 
-    fn evaluate(&mut self, instr: Instruction) -> Result<bool, VMError> {
-        match instr {
-            Instruction::Halt => return Ok(false),
-            Instruction::Set(ref a, ref b) => {
-                let v = self.get_parameter(b)?;
-                self.set_register(a, v)?
-            }
-            Instruction::Push(ref a) => {
-                let v = self.get_parameter(a)?;
+    /// The full instruction at `pc`, for error messages. Only called for instructions that decoded before.
+    fn instruction_at(&self, pc: u16) -> Instruction {
+        Instruction::decode(&self.memory[(pc as usize)..]).expect("instruction decoded before")
+    }
+
+    fn evaluate(&mut self, pc: u16, op: Op) -> Result<bool, VMError> {
+        let (a, b, c) = (op.args[0], op.args[1], op.args[2]);
+        match op.opcode {
+            0 => return Ok(false),
+            1 => {
+                let v = self.value(b);
+                self.set_register(a, v);
+            },
+            2 => {
+                let v = self.value(a);
                 self.stack_push(v);
-            }
-            Instruction::Pop(ref a) => {
+            },
+            3 => {
                 let v = self.stack_pop()?;
-                self.set_register(a, v)?;
-            }
-            Instruction::Eq(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)? == self.get_parameter(c)?;
-                self.set_register(a, if v { 1 } else { 0 })?;
-            }
-            Instruction::Gt(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)? > self.get_parameter(c)?;
-                self.set_register(a, if v { 1 } else { 0 })?;
-            }
-            Instruction::Jmp(ref a) => self.pc = self.get_parameter(a)?,
-            Instruction::Jt(ref a, ref b) => {
-                if self.get_parameter(a)? != 0 {
-                    self.pc = self.get_parameter(b)?;
+                self.set_register(a, v);
+            },
+            4 => {
+                let v = self.value(b) == self.value(c);
+                self.set_register(a, if v { 1 } else { 0 });
+            },
+            5 => {
+                let v = self.value(b) > self.value(c);
+                self.set_register(a, if v { 1 } else { 0 });
+            },
+            6 => self.pc = self.value(a),
+            7 => if self.value(a) != 0 {
+                self.pc = self.value(b);
+            },
+            8 => if self.value(a) == 0 {
+                self.pc = self.value(b);
+            },
+            9 => {
+                let v = self.value(b).wrapping_add(self.value(c));
+                self.set_register(a, v % 32768);
+            },
+            10 => {
+                let v = self.value(b).wrapping_mul(self.value(c));
+                self.set_register(a, v % 32768);
+            },
+            11 => {
+                let divisor = self.value(c);
+                if divisor == 0 {
+                    return Err(VMError::DivisionByZero { pc: pc, instruction: self.instruction_at(pc) });
                 }
-            }
-            Instruction::Jf(ref a, ref b) => {
-                if self.get_parameter(a)? == 0 {
-                    self.pc = self.get_parameter(b)?;
+                let v = self.value(b) % divisor;
+                self.set_register(a, v % 32768);
+            },
+            12 => {
+                let v = self.value(b) & self.value(c);
+                self.set_register(a, v % 32768);
+            },
+            13 => {
+                let v = self.value(b) | self.value(c);
+                self.set_register(a, v % 32768);
+            },
+            14 => {
+                let v = !self.value(b);
+                self.set_register(a, v % 32768);
+            },
+            15 => {
+                let address = self.value(b);
+                let v = match self.memory.get(address as usize) {
+                    Some(v) => *v,
+                    None => return Err(VMError::MemoryOutOfBounds { pc: pc, instruction: self.instruction_at(pc), address: address })
+                };
+                if !self.watchpoints.is_empty() {
+                    self.check_watchpoints(pc, WatchKind::Read, address, v, v);
                 }
-            }
-            Instruction::Add(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)?.wrapping_add(self.get_parameter(c)?);
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::Mult(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)?.wrapping_mul(self.get_parameter(c)?);
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::Mod(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)? % self.get_parameter(c)?;
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::And(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)? & self.get_parameter(c)?;
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::Or(ref a, ref b, ref c) => {
-                let v = self.get_parameter(b)? | self.get_parameter(c)?;
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::Not(ref a, ref b) => {
-                let v = !self.get_parameter(b)?;
-                self.set_register(a, v % 32768)?
-            }
-            Instruction::Rmem(ref a, ref b) => {
-                let v = self.memory[self.get_parameter(b)? as usize];
-                self.set_register(a, v)?;
-            }
-            Instruction::Wmem(ref a, ref b) => {
-                self.memory[self.get_parameter(a)? as usize] = self.get_parameter(b)?;
-            }
-            Instruction::Call(ref a) => {
-                let pc = self.pc;
-                self.stack_push(pc);
-                self.pc = self.get_parameter(a)?;
-            }
-            Instruction::Ret => {
+                self.set_register(a, v);
+            },
+            16 => {
+                let address = self.value(a);
+                let v = self.value(b);
+                let old = match self.memory.get(address as usize) {
+                    Some(m) => *m,
+                    None => return Err(VMError::MemoryOutOfBounds { pc: pc, instruction: self.instruction_at(pc), address: address })
+                };
+                self.record(Change::Memory(address, old));
+                self.memory[address as usize] = v;
+                self.invalidate(address);
+                if !self.watchpoints.is_empty() {
+                    self.check_watchpoints(pc, WatchKind::Write, address, old, v);
+                }
+            },
+            17 => {
+                let ret = self.pc;
+                self.stack_push(ret);
+                self.pc = self.value(a);
+            },
+            18 => {
                 self.pc = self.stack_pop()?;
-            }
-            Instruction::Out(ref a) => {
-                let param = self.get_parameter(a)?;
-                (self.output_callback)(param);
-            }
-            Instruction::In(ref a) => {
-                let v = (self.input_callback)();
-                self.set_register(a, v)?;
             },
-            Instruction::Dmp => {
+            19 => {
+                let v = self.value(a);
+                (self.output_callback)(v);
+            },
+            20 => {
+                let v = match self.input.pop_front() {
+                    Some(v) => v,
+                    None => self.input_callback.as_mut().map_or(0, |f| f())
+                };
+                self.set_register(a, v);
+            },
+            0xff => {
+                println!("At: {}", self.symbols.describe(pc));
                 println!("Registers: {:?}", self.registers);
                 println!("Stack: {:?}", self.stack);
                 println!("Memory (40xx): {:?}", &self.memory[0x4000..0x4100]);
@@ -205,18 +700,549 @@ impl<'a> VM<'a> {
                 println!("Memory (60xx): {:?}", &self.memory[0x6000..0x6100]);
                 println!("-----");
             },
-            Instruction::Noop => {}
+            _ => {}
         }
         return Ok(true);
     }
 
-    fn step(&mut self) -> Result<bool, VMError> {
-        let instr = self.load_instruction()?;
-        return self.evaluate(instr);
+    /// Executes a single instruction, returning `false` once the program halts.
+    pub fn step(&mut self) -> Result<bool, VMError> {
+        let res = self.step_recorded();
+        self.halted = match res {
+            Ok(running) => !running,
+            Err(_) => false
+        };
+        return res;
+    }
+
+    fn step_recorded(&mut self) -> Result<bool, VMError> {
+        if self.history_limit == 0 {
+            return self.step_traced();
+        }
+
+        let (pc, index) = (self.pc, self.steps);
+        let res = self.step_traced();
+        // Recorded even if the instruction failed, so that whatever it did before failing can be undone
+        if self.steps != index {
+            if self.history.len() >= self.history_limit {
+                self.history.pop_front();
+            }
+            let changes = mem::replace(&mut self.changes, Vec::new());
+            self.history.push_back(UndoStep { index: index, pc: pc, changes: changes });
+        }
+        return res;
+    }
+
+    fn step_traced(&mut self) -> Result<bool, VMError> {
+        self.watch_hits.clear();
+        let pc = self.pc;
+        let op = self.fetch()?;
+        let index = self.steps;
+
+        if self.trace_callback.is_none() {
+            self.pc += op.len;
+            self.steps += 1;
+            return self.evaluate(pc, op);
+        }
+
+        // Traces show the whole instruction, which is decoded again since the cache doesn't keep it
+        let instr = self.current_instruction()?;
+        let operands = op.args[..instr.operands().len()].iter().map(|w| self.value(*w)).collect();
+        self.pc += op.len;
+        self.steps += 1;
+        let res = self.evaluate(pc, op)?;
+        let entry = TraceEntry {
+            index: index,
+            pc: pc,
+            instruction: instr,
+            operands: operands,
+            registers: self.registers
+        };
+        if let Some(ref mut f) = self.trace_callback {
+            f(&entry);
+        }
+        return Ok(res);
     }
 
     pub fn execute(&mut self) -> Result<(), VMError> {
         while self.step()? {};
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Whether the next instruction is an `in` with no input left to read and no input callback to ask.
+    fn needs_input(&mut self) -> Result<bool, VMError> {
+        if !self.input.is_empty() || self.input_callback.is_some() {
+            return Ok(false);
+        }
+        Ok(self.fetch()?.opcode == 20)
+    }
+
+    /// Runs until the program halts, fails, or needs input that hasn't been provided and there is no input
+    /// callback for. Also stops after `max_steps` instructions, and before any instruction whose address
+    /// `stop_at` returns `true` for. At least one instruction is executed unless the program is already halted
+    /// or waiting for input, so continuing from a breakpoint moves past it.
+    pub fn run_until<F>(&mut self, max_steps: Option<u64>, mut stop_at: F) -> StopReason where F: FnMut(u16) -> bool {
+        if self.halted {
+            return StopReason::Halted;
+        }
+
+        let mut n = 0;
+        loop {
+            if max_steps.map_or(false, |max| n >= max) {
+                return StopReason::StepLimit;
+            }
+            match self.needs_input() {
+                Ok(true) => return StopReason::NeedsInput,
+                Ok(false) => {},
+                Err(e) => return StopReason::Error(e)
+            }
+
+            match self.step() {
+                Ok(true) => {},
+                Ok(false) => return StopReason::Halted,
+                Err(e) => return StopReason::Error(e)
+            }
+            n += 1;
+
+            if let Some(hit) = self.watch_stop() {
+                return StopReason::Watchpoint(hit);
+            }
+            if stop_at(self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugEvent {
+    Stepped,
+    Breakpoint(u16),
+    /// The instruction just executed triggered a watchpoint that isn't set to only log.
+    Watchpoint(WatchHit),
+    Halted,
+    /// The next instruction is an `in`, and there is no input left and no input callback. Nothing was executed;
+    /// give more input with `VM::provide_input` and step again.
+    NeedsInput,
+    /// Stepping backwards ran out of undo log.
+    HistoryStart
+}
+
+/// Drives a `VM` one instruction at a time, stopping at breakpoints and watchpoints.
+pub struct Debugger<'a> {
+    vm: VM<'a>,
+    breakpoints: BTreeSet<u16>
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: VM<'a>) -> Self {
+        return Debugger {
+            vm: vm,
+            breakpoints: BTreeSet::new()
+        };
+    }
+
+    pub fn vm(&self) -> &VM<'a> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM<'a> {
+        &mut self.vm
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.vm.is_halted()
+    }
+
+    /// Returns `false` if there already was a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    /// Returns `false` if there was no breakpoint at `addr`.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> Vec<u16> {
+        self.breakpoints.iter().cloned().collect()
+    }
+
+    /// Executes one instruction. Reports `Watchpoint` if it triggered a watchpoint, or `Breakpoint` if the next
+    /// instruction has a breakpoint on it.
+    pub fn step(&mut self) -> Result<DebugEvent, VMError> {
+        if self.vm.is_halted() {
+            return Ok(DebugEvent::Halted);
+        }
+        if self.vm.needs_input()? {
+            return Ok(DebugEvent::NeedsInput);
+        }
+        if !self.vm.step()? {
+            return Ok(DebugEvent::Halted);
+        }
+
+        if let Some(hit) = self.vm.watch_stop() {
+            return Ok(DebugEvent::Watchpoint(hit));
+        }
+
+        let pc = self.vm.pc();
+        if self.breakpoints.contains(&pc) {
+            return Ok(DebugEvent::Breakpoint(pc));
+        }
+        Ok(DebugEvent::Stepped)
+    }
+
+    /// Runs until the program halts, reaches a breakpoint or triggers a watchpoint. Always executes at least one instruction,
+    /// so continuing from a breakpoint moves past it.
+    pub fn cont(&mut self) -> Result<DebugEvent, VMError> {
+        loop {
+            match self.step()? {
+                DebugEvent::Stepped => {},
+                e => return Ok(e)
+            }
+        }
+    }
+
+    /// Undoes one instruction. Reports `Breakpoint` if the instruction that is now next has a breakpoint on it.
+    pub fn step_back(&mut self) -> DebugEvent {
+        if !self.vm.step_back() {
+            return DebugEvent::HistoryStart;
+        }
+
+        let pc = self.vm.pc();
+        if self.breakpoints.contains(&pc) {
+            return DebugEvent::Breakpoint(pc);
+        }
+        DebugEvent::Stepped
+    }
+
+    /// Runs backwards until reaching a breakpoint or the start of the undo log.
+    pub fn reverse_cont(&mut self) -> DebugEvent {
+        loop {
+            match self.step_back() {
+                DebugEvent::Stepped => {},
+                e => return e
+            }
+        }
+    }
+
+    /// Runs backwards to just before the last instruction that changed something matching `f`, and returns
+    /// its step count. Returns `None` without moving if no instruction in the undo log did.
+    pub fn reverse_to_change<F>(&mut self, f: F) -> Option<u64> where F: Fn(&Change) -> bool {
+        let index = self.vm.last_change(f)?.index;
+        while self.vm.steps() > index && self.vm.step_back() {}
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assembler::tests::image;
+    use ::instruction::Register;
+    use std::cell::RefCell;
+    use std::io::ErrorKind;
+    use std::rc::Rc;
+
+    fn load(src: &str) -> VM<'static> {
+        let mut vm = VM::new();
+        let words = image(src);
+        vm.memory[..words.len()].copy_from_slice(&words);
+        vm
+    }
+
+    fn state(vm: &VM) -> (u16, [u16; 8], Vec<u16>, u16) {
+        (vm.pc, vm.registers, vm.stack.clone(), vm.memory[100])
+    }
+
+    #[test]
+    fn steps_back_over_call_ret_and_wmem() {
+        let mut vm = load("    set $0 5\n    push 9\n    call :f\n    pop $1\n    halt\n\
+                           f:  wmem 100 $0\n    add $0 $0 1\n    ret\n");
+        vm.set_history_limit(100);
+        let mut states = Vec::new();
+        loop {
+            states.push(state(&vm));
+            if !vm.step().unwrap() {
+                break;
+            }
+        }
+        assert_eq!(vm.registers[0..2], [6, 9]);
+        assert_eq!(vm.memory[100], 5);
+
+        for s in states.iter().rev() {
+            assert!(vm.step_back());
+            assert_eq!(state(&vm), *s);
+        }
+        assert!(!vm.step_back());
+        assert_eq!(vm.steps(), 0);
+    }
+
+    #[test]
+    fn history_is_limited() {
+        let mut vm = load("    set $0 1\n    set $0 2\n    set $0 3\n    halt\n");
+        vm.set_history_limit(2);
+        vm.execute().unwrap();
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!((vm.pc, vm.registers[0]), (6, 2));
+    }
+
+    fn collect_output(vm: &mut VM) -> Rc<RefCell<String>> {
+        let out = Rc::new(RefCell::new(String::new()));
+        let sink = out.clone();
+        vm.set_output_callback(move |c| sink.borrow_mut().push(c as u8 as char));
+        out
+    }
+
+    const SELF_MODIFYING: &'static str = "start: out 'a'\n    jt $0 :end\n    set $0 1\n    wmem 1 'b'\n    jmp :start\nend: halt\n";
+
+    #[test]
+    fn runs_code_overwritten_after_decoding() {
+        let mut vm = load(SELF_MODIFYING);
+        let out = collect_output(&mut vm);
+        vm.execute().unwrap();
+        assert_eq!(*out.borrow(), "ab");
+    }
+
+    #[test]
+    fn undoing_wmem_drops_decoded_code() {
+        let mut vm = load(SELF_MODIFYING);
+        vm.set_history_limit(100);
+        let out = collect_output(&mut vm);
+        vm.execute().unwrap();
+        while vm.step_back() {}
+        assert_eq!(vm.memory[1], 97);
+
+        out.borrow_mut().clear();
+        vm.execute().unwrap();
+        assert_eq!(*out.borrow(), "ab");
+    }
+
+    fn watch(vm: &mut VM, kind: WatchKind, condition: Option<Condition>, log: bool) -> usize {
+        vm.add_watchpoint(Watchpoint { start: 100, end: 100, kind: kind, condition: condition, log: log })
+    }
+
+    const WATCHED: &'static str = "    wmem 100 5\n    wmem 100 5\n    rmem $0 100\n    wmem 100 7\n    wmem 101 7\n    halt\n";
+
+    #[test]
+    fn watchpoints_filter_accesses() {
+        let mut vm = load(WATCHED);
+        let write = watch(&mut vm, WatchKind::Write, None, false);
+        let change = watch(&mut vm, WatchKind::Change, None, false);
+        let seven = watch(&mut vm, WatchKind::Write, Some(Condition::Equal(7)), false);
+        let read = watch(&mut vm, WatchKind::Read, Some(Condition::Greater(4)), false);
+        watch(&mut vm, WatchKind::Read, Some(Condition::Less(5)), false);
+
+        let mut hits = Vec::new();
+        while vm.step().unwrap() {
+            hits.push(vm.watch_hits().iter().map(|h| h.id).collect::<Vec<_>>());
+        }
+        assert_eq!(hits, vec![vec![write, change], vec![write], vec![read], vec![write, change, seven], vec![]]);
+    }
+
+    #[test]
+    fn run_until_stops_at_watchpoints_and_collects_logs() {
+        let mut vm = load(WATCHED);
+        watch(&mut vm, WatchKind::Write, None, true);
+        let change = watch(&mut vm, WatchKind::Change, Some(Condition::NotEqual(5)), false);
+
+        match vm.run_until(None, |_| false) {
+            StopReason::Watchpoint(hit) => assert_eq!((hit.id, hit.pc, hit.old, hit.value), (change, 9, 5, 7)),
+            r => panic!("unexpected {:?}", r)
+        }
+        let log: Vec<(u16, u16)> = vm.take_watch_log().iter().map(|h| (h.pc, h.value)).collect();
+        assert_eq!(log, vec![(0, 5), (3, 5), (9, 7)]);
+        match vm.run_until(None, |_| false) {
+            StopReason::Halted => {},
+            r => panic!("unexpected {:?}", r)
+        }
+        assert!(vm.take_watch_log().is_empty());
+    }
+
+    #[test]
+    fn run_until_waits_for_input() {
+        let mut vm = load("    in $0\n    in $1\n    halt\n");
+        match vm.run_until(None, |_| false) {
+            StopReason::NeedsInput => {},
+            r => panic!("unexpected {:?}", r)
+        }
+        assert_eq!(vm.steps(), 0);
+
+        vm.provide_input("a").unwrap();
+        match vm.run_until(None, |_| false) {
+            StopReason::NeedsInput => {},
+            r => panic!("unexpected {:?}", r)
+        }
+        assert_eq!((vm.pc, vm.registers[0]), (2, 97));
+
+        vm.provide_input("é").unwrap();
+        match vm.run_until(None, |_| false) {
+            StopReason::Halted => {},
+            r => panic!("unexpected {:?}", r)
+        }
+        assert_eq!(vm.registers[1], 233);
+        match vm.run_until(None, |_| false) {
+            StopReason::Halted => {},
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn run_until_stops_at_breakpoints_and_step_limits() {
+        let mut vm = load("    noop\n    noop\n    noop\n    halt\n");
+        match vm.run_until(Some(2), |_| false) {
+            StopReason::StepLimit => assert_eq!(vm.pc, 2),
+            r => panic!("unexpected {:?}", r)
+        }
+        match vm.run_until(None, |pc| pc == 2 || pc == 3) {
+            StopReason::Breakpoint(pc) => assert_eq!(pc, 3),
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    const LOOP: &'static str = "start: add $0 $0 1\n    eq $1 $0 3\n    jf $1 :start\nend: halt\n";
+
+    #[test]
+    fn debugger_steps_and_continues_to_breakpoints() {
+        let mut dbg = Debugger::new(load(LOOP));
+        assert!(dbg.add_breakpoint(4));
+        assert!(!dbg.add_breakpoint(4));
+        assert_eq!(dbg.step().unwrap(), DebugEvent::Breakpoint(4));
+        assert_eq!(dbg.cont().unwrap(), DebugEvent::Breakpoint(4));
+        assert_eq!((dbg.vm().registers()[0], dbg.vm().steps()), (2, 4));
+
+        assert!(dbg.remove_breakpoint(4));
+        assert!(dbg.breakpoints().is_empty());
+        dbg.add_breakpoint(11);
+        assert_eq!(dbg.cont().unwrap(), DebugEvent::Breakpoint(11));
+        assert_eq!(dbg.vm().registers()[0], 3);
+        assert_eq!(dbg.step().unwrap(), DebugEvent::Halted);
+    }
+
+    #[test]
+    fn debugger_breaks_at_labels() {
+        let assembly = ::assembler::assemble_program("test.synasm", LOOP).unwrap();
+        let symbols = SymbolTable::from_labels(assembly.labels());
+        let mut dbg = Debugger::new(load(LOOP));
+        dbg.add_breakpoint(symbols.get("end").unwrap());
+        assert_eq!(dbg.cont().unwrap(), DebugEvent::Breakpoint(11));
+    }
+
+    #[test]
+    fn debugger_stays_halted() {
+        let mut dbg = Debugger::new(load("    set $0 1\n    halt\n    set $0 2\n"));
+        dbg.vm_mut().set_history_limit(10);
+        assert!(!dbg.is_halted());
+        assert_eq!(dbg.cont().unwrap(), DebugEvent::Halted);
+        assert!(dbg.is_halted() && dbg.vm().is_halted());
+        assert_eq!(dbg.step().unwrap(), DebugEvent::Halted);
+        assert_eq!(dbg.cont().unwrap(), DebugEvent::Halted);
+        assert_eq!((dbg.vm().pc(), dbg.vm().steps(), dbg.vm().registers()[0]), (4, 2, 1));
+
+        assert_eq!(dbg.step_back(), DebugEvent::Stepped);
+        assert!(!dbg.is_halted());
+        assert_eq!(dbg.step().unwrap(), DebugEvent::Halted);
+    }
+
+    #[test]
+    fn debugger_waits_for_input() {
+        let mut dbg = Debugger::new(load("    in $0\n    halt\n"));
+        assert_eq!(dbg.step().unwrap(), DebugEvent::NeedsInput);
+        assert_eq!(dbg.vm().steps(), 0);
+        dbg.vm_mut().provide_input("é").unwrap();
+        assert_eq!(dbg.step().unwrap(), DebugEvent::Stepped);
+        assert_eq!(dbg.vm().registers()[0], 233);
+    }
+
+    fn error(src: &str) -> VMError {
+        let mut vm = load(src);
+        match vm.execute() {
+            Err(e) => e,
+            Ok(()) => panic!("expected an error")
+        }
+    }
+
+    const BIG: &'static str = "    rmem $1 :big\n";
+    const BIG_DATA: &'static str = "big: .word 40000\n";
+
+    #[test]
+    fn reports_memory_access_out_of_bounds() {
+        match error(&format!("{}    rmem $0 $1\n{}", BIG, BIG_DATA)) {
+            VMError::MemoryOutOfBounds { pc: 3, instruction: Instruction::Rmem(Register(0), Parameter::Register(Register(1))), address: 40000 } => {},
+            e => panic!("unexpected {:?}", e)
+        }
+        let e = error(&format!("{}    wmem $1 7\n{}", BIG, BIG_DATA));
+        match e {
+            VMError::MemoryOutOfBounds { pc: 3, instruction: Instruction::Wmem(Parameter::Register(Register(1)), Parameter::Literal(7)), address: 40000 } => {},
+            ref e => panic!("unexpected {:?}", e)
+        }
+        assert_eq!(e.to_string(), "Memory address 40000 out of bounds at x0003: wmem $1 7");
+        assert_eq!(e.pc(), Some(3));
+    }
+
+    #[test]
+    fn reports_pc_out_of_bounds() {
+        match error(&format!("{}    push $1\n    ret\n{}", BIG, BIG_DATA)) {
+            VMError::PcOutOfBounds(40000) => {},
+            e => panic!("unexpected {:?}", e)
+        }
+        let mut vm = VM::new();
+        vm.memory[0..2].copy_from_slice(&[6, 32767]);
+        vm.memory[32767] = 1;
+        match vm.execute() {
+            Err(VMError::PcOutOfBounds(32767)) => {},
+            r => panic!("unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn reports_division_by_zero() {
+        let e = error("    noop\n    mod $2 5 $3\n");
+        match e {
+            VMError::DivisionByZero { pc: 1, instruction: Instruction::Mod(Register(2), Parameter::Literal(5), Parameter::Register(Register(3))) } => {},
+            ref e => panic!("unexpected {:?}", e)
+        }
+        assert_eq!(e.to_string(), "Division by zero at x0001: mod $2 5 $3");
+    }
+
+    #[test]
+    fn rejects_input_outside_a_word() {
+        let mut vm = VM::new();
+        match vm.provide_input("a\u{8000}") {
+            Err(VMError::InvalidInput('\u{8000}')) => {},
+            r => panic!("unexpected {:?}", r)
+        }
+        assert!(vm.input.is_empty());
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut vm = VM::new();
+        vm.memory[0..6].copy_from_slice(&[2, 7, 2, 8, 1, 32771]);
+        vm.step().unwrap();
+        vm.step().unwrap();
+        let mut out = Vec::new();
+        vm.snapshot().write(&mut out).unwrap();
+
+        let snapshot = Snapshot::read(&mut &out[..]).unwrap();
+        assert_eq!(snapshot.pc, 4);
+        assert_eq!(snapshot.stack, vec![7, 8]);
+        assert_eq!(&snapshot.memory[0..6], &[2, 7, 2, 8, 1, 32771]);
+    }
+
+    #[test]
+    fn rejects_snapshots_with_a_wrong_stack_depth() {
+        let mut out = Vec::new();
+        VM::new().snapshot().write(&mut out).unwrap();
+        let depth_at = out.len() - 4;
+        out[depth_at..].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        match Snapshot::read(&mut &out[..]) {
+            Err(ref e) if e.kind() == ErrorKind::InvalidData => {},
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("expected an error")
+        }
+    }
+}
+